@@ -0,0 +1,111 @@
+//! Attribute macros for the zero_to_async executor
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Error, FnArg, ItemFn, LitInt, Pat, ReturnType, Visibility};
+
+/// Turn an async function into a task with statically allocated storage
+///
+/// ```ignore
+/// #[task(pool_size = 2)]
+/// async fn blink(led: Led, period: TickDuration) { ... }
+///
+/// spawner.spawn_task(blink(led, TickDuration::millis(500)))?;
+/// ```
+///
+/// The function keeps its name and arguments, but returns a `SpawnToken`
+/// instead of a future. The future is stored in a static pool sized for it,
+/// with room for `pool_size` instances (1 if not given), so that many copies
+/// of the task can run at the same time. Arguments must live forever, e.g.
+/// endpoints of a `Channel` kept in a `static`.
+#[proc_macro_attribute]
+pub fn task(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut pool_size = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("pool_size") {
+            pool_size = Some(meta.value()?.parse::<LitInt>()?);
+            Ok(())
+        } else {
+            Err(meta.error("Unsupported task argument, expected `pool_size`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let item = parse_macro_input!(item as ItemFn);
+
+    match expand_task(pool_size, item) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand_task(pool_size: Option<LitInt>, item: ItemFn) -> syn::Result<TokenStream2> {
+    let sig = &item.sig;
+
+    if sig.asyncness.is_none() {
+        return Err(Error::new_spanned(sig.fn_token, "Task functions must be async"));
+    }
+    if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+        return Err(Error::new_spanned(&sig.generics, "Task functions can't be generic, their pool has a fixed size"));
+    }
+
+    let pool_size = match pool_size {
+        Some(lit) => {
+            if lit.base10_parse::<usize>()? == 0 {
+                return Err(Error::new_spanned(lit, "Pool size must be at least 1"));
+            }
+            lit.base10_parse::<usize>()?
+        }
+        None => 1,
+    };
+
+    // The generated function takes the same arguments, without `mut`
+    let mut arg_names = Vec::new();
+    let mut args = Vec::new();
+    for arg in &sig.inputs {
+        let FnArg::Typed(arg) = arg else {
+            return Err(Error::new_spanned(arg, "Task functions can't take self"));
+        };
+
+        match &*arg.pat {
+            Pat::Ident(pat) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                let name = &pat.ident;
+                let ty = &arg.ty;
+                args.push(quote!(#name: #ty));
+                arg_names.push(name.clone());
+            }
+            pat => return Err(Error::new_spanned(pat, "Task arguments must be plain identifiers")),
+        }
+    }
+
+    let output = match &sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+
+    let attrs = &item.attrs;
+    let vis = &item.vis;
+    let name = &sig.ident;
+    let task_name = format_ident!("__{}_task", name);
+
+    // The original function, polled as the task
+    let mut task_fn = item.clone();
+    task_fn.attrs.clear();
+    task_fn.vis = Visibility::Inherited;
+    task_fn.sig.ident = task_name.clone();
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis fn #name(#(#args),*) -> ::zero_to_async::executor::SpawnToken<#output> {
+            #task_fn
+
+            const POOL_SIZE: usize = #pool_size;
+            static POOL: ::zero_to_async::executor::TaskPool<
+                POOL_SIZE,
+                { ::zero_to_async::executor::task_size(&#task_name) },
+            > = ::zero_to_async::executor::TaskPool::new();
+
+            POOL.claim(#task_name(#(#arg_names),*))
+        }
+    })
+}
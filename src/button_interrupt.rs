@@ -1,11 +1,14 @@
 use embedded_hal::digital::PinState;
 
 use core::{
+    cell::RefCell,
+    convert::Infallible,
     future::poll_fn,
-    sync::atomic::{AtomicUsize, Ordering},
-    task::Poll,
+    task::{Poll, Waker},
 };
 
+use critical_section::Mutex;
+
 use stm32f0xx_hal::{
     prelude::_embedded_hal_gpio_InputPin,
     pac::{interrupt, Interrupt, EXTI, SYSCFG},
@@ -13,22 +16,23 @@ use stm32f0xx_hal::{
 };
 use rtt_target::rprintln;
 
-use crate::executor::{wake_task, ExtWaker};
+use crate::executor;
 
 const MAX_CHANNELS_USED: usize = 1;
-const INVALID_TASK_ID: usize = 0xFFFF_FFFF;
 
-static WAKE_TASKS: [AtomicUsize; MAX_CHANNELS_USED] = [AtomicUsize::new(INVALID_TASK_ID); MAX_CHANNELS_USED];
+// Waker of the task waiting on each input channel
+static WAKERS: Mutex<RefCell<[Option<Waker>; MAX_CHANNELS_USED]>> =
+    Mutex::new(RefCell::new([const { None }; MAX_CHANNELS_USED]));
 
-fn map_exti_line_to_wake_task(exti_line: usize) -> usize {
+fn map_exti_line_to_channel(exti_line: usize) -> Option<usize> {
     match exti_line {
-        13 => { 0 }
-        _ => {INVALID_TASK_ID},
+        13 => Some(0),
+        _ => None,
     }
 }
 
-pub struct InputChannel {
-    pin: Pin<Input<PullUp>>,
+pub struct InputChannel<P = Pin<Input<PullUp>>> {
+    pin: P,
     exti_line: usize,
 }
 
@@ -48,14 +52,36 @@ impl InputChannel {
 
         channel
     }
+}
+
+#[cfg(feature = "std")]
+impl InputChannel<crate::sim::SimInput> {
+    /// Input channel on a simulated pin, woken by scripted edges instead of EXTI
+    pub fn new_sim(pin: crate::sim::SimInput) -> Self {
+        let exti_line = pin.exti_line();
+        Self { pin, exti_line }
+    }
+}
 
+impl<P: _embedded_hal_gpio_InputPin<Error = Infallible>> InputChannel<P> {
     pub async fn wait_for(&mut self, ready_state: PinState) {
         poll_fn(|cx| {
             if ready_state == PinState::from(self.pin.is_high().unwrap()) {
-                Poll::Ready(())
+                // A level that is already there would never make the task yield
+                executor::poll_budget(cx)
             } else {
-                let exti_line = map_exti_line_to_wake_task(self.exti_line);
-                WAKE_TASKS[exti_line].store(cx.waker().task_id(), Ordering::Relaxed);
+                let channel = map_exti_line_to_channel(self.exti_line)
+                    .expect("No input channel for EXTI line");
+
+                critical_section::with(|cs| {
+                    let mut wakers = WAKERS.borrow(cs).borrow_mut();
+                    let waker = &mut wakers[channel];
+
+                    // Only clone if the task is polled from somewhere new
+                    if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                        *waker = Some(cx.waker().clone());
+                    }
+                });
                 Poll::Pending
             }
         }).await
@@ -113,12 +139,19 @@ fn EXTI4_15() {
         // Clear the pending bit
         exti.pr.write(|w| w.pif13().set_bit());
 
-        // Wake the corresponding task
-        let task_id = WAKE_TASKS[map_exti_line_to_wake_task(13)].load(Ordering::Relaxed);
+        wake_exti_line(13);
+    }
+}
+
+/// Wake the task waiting on an EXTI line, called for every detected edge
+pub(crate) fn wake_exti_line(exti_line: usize) {
+    let Some(channel) = map_exti_line_to_channel(exti_line) else {
+        return;
+    };
+
+    let waker = critical_section::with(|cs| WAKERS.borrow(cs).borrow_mut()[channel].clone());
 
-        if task_id != INVALID_TASK_ID {
-            rprintln!("Waking task {}", task_id);
-            wake_task(task_id);
-        }
+    if let Some(waker) = waker {
+        waker.wake();
     }
 }
@@ -4,83 +4,410 @@ use core::{
     task::{Context, Poll, Waker},
 };
 
-pub struct Channel<T> {
-    item: Cell<Option<T>>,
-    waker: RefCell<Option<Waker>>,
+use critical_section::{CriticalSection, Mutex};
+use heapless::Deque;
+
+use crate::executor;
+
+// Constants
+const MAX_WAITING_SENDERS: usize = executor::TOTAL_TASKS; // Every task of an executor can wait
+
+/// Error returned by `Receiver::receive` once the channel is closed and drained
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Closed;
+
+/// Error returned by `Sender::send` on a closed channel, with the item that was not sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Error returned by `try_send`, with the item that was not sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// There is no space, the receiver has not caught up
+    Full(T),
+    /// The channel is closed, nobody will receive the item
+    Closed(T),
 }
 
-impl<T> Channel<T> {
-    pub fn new() -> Self {
-        Self {
-            item: Cell::new(None),
-            waker: RefCell::new(None),
+/// Entry of one waiting future in a `WaitQueue`
+pub(crate) type Ticket = u32;
+
+/// Futures waiting on a channel, woken first come first served
+///
+/// Every waiting future has its own entry, even if several wait in the same
+/// task, so dropping one of them leaves the others in line.
+pub(crate) struct WaitQueue<const M: usize> {
+    entries: Deque<(Ticket, Waker), M>,
+    next_ticket: Ticket,
+}
+
+impl<const M: usize> WaitQueue<M> {
+    pub(crate) const fn new() -> Self {
+        Self { entries: Deque::new(), next_ticket: 0 }
+    }
+
+    /// Line up the future holding `ticket`, at the front if it was woken before but missed its turn
+    ///
+    /// A future still in line only has its waker refreshed. A full line is
+    /// handed back to be woken, so nobody waits forever. It only fills up
+    /// with more waiting futures than one executor runs tasks.
+    #[must_use]
+    pub(crate) fn register(&mut self, ticket: &mut Option<Ticket>, waker: &Waker, keep_turn: bool) -> Option<Self> {
+        if let Some(ticket) = *ticket
+            && let Some((_, waiting)) = self.entries.iter_mut().find(|(id, _)| *id == ticket)
+        {
+            if !waiting.will_wake(waker) {
+                *waiting = waker.clone();
+            }
+            return None;
         }
+
+        let full = self.entries.is_full().then(|| self.take());
+        let entry = (self.next_ticket, waker.clone());
+        *ticket = Some(self.next_ticket);
+        self.next_ticket = self.next_ticket.wrapping_add(1);
+
+        // Cannot fail: there is space after taking a full line
+        if keep_turn {
+            self.entries.push_front(entry).ok();
+        } else {
+            self.entries.push_back(entry).ok();
+        }
+        full
     }
 
-    pub fn get_sender(&self) -> Sender<'_, T> {
-        Sender { channel: self }
+    /// Next future in line, it has to register again if it still has to wait
+    pub(crate) fn next(&mut self) -> Option<Waker> {
+        self.entries.pop_front().map(|(_, waker)| waker)
     }
 
-    pub fn get_receiver(&self) -> Receiver<'_, T> {
-        Receiver {
-            channel: self,
-            state: ReceiverState::Init,
+    /// Take the entry of `ticket` out of line, false if it was not waiting anymore
+    pub(crate) fn remove(&mut self, ticket: Ticket) -> bool {
+        let mut found = false;
+        // Rotate once through the line, dropping the entry on the way
+        for _ in 0..self.entries.len() {
+            if let Some(entry) = self.entries.pop_front() {
+                if entry.0 == ticket {
+                    found = true;
+                } else {
+                    // Cannot fail: an entry was just taken out
+                    self.entries.push_back(entry).ok();
+                }
+            }
         }
+        found
     }
 
-    fn send(&self, item: T) {
-        self.item.replace(Some(item));
+    /// Take a future that is done out of line, if it still is in it
+    pub(crate) fn done(&mut self, ticket: &mut Option<Ticket>) {
+        if let Some(ticket) = ticket.take() {
+            self.remove(ticket);
+        }
+    }
 
-        if let Some(waker) = self.waker.borrow().as_ref() {
-            waker.wake_by_ref();
+    /// Everyone in line, leaving it empty
+    ///
+    /// Tickets keep counting, so a woken future's old ticket is not handed out again.
+    pub(crate) fn take(&mut self) -> Self {
+        Self { entries: core::mem::replace(&mut self.entries, Deque::new()), next_ticket: 0 }
+    }
+
+    pub(crate) fn wake_all(mut self) {
+        while let Some(waker) = self.next() {
+            waker.wake();
         }
     }
+}
+
+/// Place in line of a waiting send or receive, `leave` gives it up when the future is dropped
+pub(crate) struct Place<F: FnMut(Ticket)> {
+    pub(crate) ticket: Option<Ticket>,
+    leave: F,
+}
+
+impl<F: FnMut(Ticket)> Place<F> {
+    pub(crate) fn new(leave: F) -> Self {
+        Self { ticket: None, leave }
+    }
+}
+
+impl<F: FnMut(Ticket)> Drop for Place<F> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket.take() {
+            (self.leave)(ticket);
+        }
+    }
+}
+
+/// Sender count of a channel, it closes once the last sender is dropped or one calls `close`
+pub(crate) struct Senders {
+    count: Mutex<Cell<usize>>,
+    closed: Mutex<Cell<bool>>,
+}
+
+impl Senders {
+    pub(crate) const fn new() -> Self {
+        Self { count: Mutex::new(Cell::new(0)), closed: Mutex::new(Cell::new(false)) }
+    }
+
+    pub(crate) fn add(&self) {
+        critical_section::with(|cs| {
+            let count = self.count.borrow(cs);
+            count.set(count.get() + 1);
+        });
+    }
+
+    /// Count a dropped sender, true if it was the last one and the channel has to close
+    #[must_use]
+    pub(crate) fn remove(&self) -> bool {
+        critical_section::with(|cs| {
+            let count = self.count.borrow(cs);
+            count.set(count.get() - 1);
+            count.get() == 0
+        })
+    }
+
+    pub(crate) fn close(&self, cs: CriticalSection) {
+        self.closed.borrow(cs).set(true);
+    }
+
+    pub(crate) fn is_closed(&self, cs: CriticalSection) -> bool {
+        self.closed.borrow(cs).get()
+    }
+}
+
+/// Channel a send can wait on for space
+pub(crate) trait SendQueue<T> {
+    /// Add `item` at the back, if full `waiting` lines up to be woken once there is space
+    ///
+    /// A waiting send that is done leaves the line.
+    fn push(&self, item: T, waiting: Option<(&mut Option<Ticket>, &Waker)>) -> Result<(), TrySendError<T>>;
+
+    /// Take a dropped send out of line
+    fn leave_senders(&self, ticket: Ticket);
+}
+
+/// Send `item`, waiting in line for space if the channel is full
+pub(crate) async fn send<T>(channel: &impl SendQueue<T>, item: T) -> Result<(), SendError<T>> {
+    let mut item = Some(item);
+    let mut place = Place::new(|ticket| channel.leave_senders(ticket));
+
+    poll_fn(|cx| {
+        // Cannot fail: the item is only taken once it is sent
+        let Some(pending) = item.take() else {
+            return Poll::Ready(Ok(()));
+        };
+
+        match channel.push(pending, Some((&mut place.ticket, cx.waker()))) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Full(pending)) => {
+                item = Some(pending);
+                Poll::Pending
+            }
+            Err(TrySendError::Closed(pending)) => Poll::Ready(Err(SendError(pending))),
+        }
+    }).await
+}
+
+/// Channel holding up to `N` items in a ring buffer, received in FIFO order
+///
+/// Everything is behind a critical section, so the channel can live in a
+/// `static` and interrupt handlers can push into it with `try_send`.
+///
+/// The channel closes once a sender calls `close` or the last `Sender` is
+/// dropped. The receiver still gets the items sent before that. Interrupt
+/// handlers sending with `Channel::try_send` do not count as senders.
+pub struct Channel<T, const N: usize> {
+    items: Mutex<RefCell<Deque<T, N>>>,
+    receiver_waker: Mutex<RefCell<Option<Waker>>>,
+    sender_wakers: Mutex<RefCell<WaitQueue<MAX_WAITING_SENDERS>>>, // Senders waiting for space
+    senders: Senders,
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            items: Mutex::new(RefCell::new(Deque::new())),
+            receiver_waker: Mutex::new(RefCell::new(None)),
+            sender_wakers: Mutex::new(RefCell::new(WaitQueue::new())),
+            senders: Senders::new(),
+        }
+    }
+
+    pub fn get_sender(&self) -> Sender<'_, T, N> {
+        self.senders.add();
+        Sender { channel: self }
+    }
+
+    pub fn get_receiver(&self) -> Receiver<'_, T, N> {
+        Receiver { channel: self }
+    }
+
+    /// Send `item` if there is space, otherwise hand it back
+    ///
+    /// Never waits, so it is safe to call from an interrupt handler. The
+    /// receiving task is woken through its executor.
+    pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        self.push(item, None)
+    }
 
     fn receive(&self) -> Option<T> {
-        self.item.take()
+        let (item, sender_wakers) = critical_section::with(|cs| {
+            let item = self.items.borrow(cs).borrow_mut().pop_front()?;
+            Some((item, self.sender_wakers.borrow(cs).borrow_mut().take()))
+        })?;
+
+        // There is space now, all waiting senders try again and the losers register anew
+        sender_wakers.wake_all();
+        Some(item)
+    }
+
+    /// Whether there is an item to receive, `Err(Closed)` once closed and drained
+    fn has_item(&self) -> Result<bool, Closed> {
+        critical_section::with(|cs| {
+            if !self.items.borrow(cs).borrow().is_empty() {
+                Ok(true)
+            } else if self.senders.is_closed(cs) {
+                Err(Closed)
+            } else {
+                Ok(false)
+            }
+        })
+    }
+
+    /// Close the channel and wake everyone waiting on it
+    fn close(&self) {
+        let (receiver_waker, sender_wakers) = critical_section::with(|cs| {
+            self.senders.close(cs);
+            (self.receiver_waker.borrow(cs).take(), self.sender_wakers.borrow(cs).borrow_mut().take())
+        });
+
+        if let Some(waker) = receiver_waker {
+            waker.wake();
+        }
+        sender_wakers.wake_all();
+    }
+
+    fn register(&self, waker: &Waker) {
+        critical_section::with(|cs| {
+            let mut receiver_waker = self.receiver_waker.borrow(cs).borrow_mut();
+
+            // Only clone if the receiver is polled from somewhere new
+            if !receiver_waker.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                *receiver_waker = Some(waker.clone());
+            }
+        });
     }
+}
+
+impl<T, const N: usize> SendQueue<T> for Channel<T, N> {
+    fn push(&self, item: T, waiting: Option<(&mut Option<Ticket>, &Waker)>) -> Result<(), TrySendError<T>> {
+        let (result, full_line) = critical_section::with(|cs| {
+            let mut sender_wakers = self.sender_wakers.borrow(cs).borrow_mut();
+            let (ticket, waker) = match waiting {
+                Some((ticket, waker)) => (ticket, Some(waker)),
+                None => (&mut None, None),
+            };
+
+            let result = if self.senders.is_closed(cs) {
+                Err(TrySendError::Closed(item))
+            } else if let Err(item) = self.items.borrow(cs).borrow_mut().push_back(item) {
+                // Registered in the same critical section, so no space is made in between
+                let full_line = waker.and_then(|waker| sender_wakers.register(ticket, waker, false));
+                return (Err(TrySendError::Full(item)), full_line);
+            } else {
+                Ok(self.receiver_waker.borrow(cs).borrow().clone())
+            };
+
+            sender_wakers.done(ticket);
+            (result, None)
+        });
 
-    fn register(&self, waker: Waker) {
-        self.waker.replace(Some(waker));
+        if let Some(full_line) = full_line {
+            full_line.wake_all();
+        }
+        if let Some(waker) = result? {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn leave_senders(&self, ticket: Ticket) {
+        critical_section::with(|cs| self.sender_wakers.borrow(cs).borrow_mut().remove(ticket));
     }
 }
 
-pub struct Sender<'a, T> {
-    channel: &'a Channel<T>,
+/// Sending end of a channel, clone it for more senders
+pub struct Sender<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+impl<T, const N: usize> Sender<'_, T, N> {
+    /// Send `item`, waiting for space if the channel is full
+    pub async fn send(&self, item: T) -> Result<(), SendError<T>> {
+        send(self.channel, item).await
+    }
+
+    /// Send `item` if there is space, otherwise hand it back
+    pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        self.channel.try_send(item)
+    }
+
+    /// Close the channel for all senders, the receiver still gets the items sent so far
+    pub fn close(&self) {
+        self.channel.close();
+    }
 }
 
-impl<T> Sender<'_, T> {
-    pub fn send(&self, item: T) {
-        self.channel.send(item);
+impl<T, const N: usize> Clone for Sender<'_, T, N> {
+    fn clone(&self) -> Self {
+        self.channel.get_sender()
     }
 }
 
-enum ReceiverState {
-    Init,
-    Wait,
+impl<T, const N: usize> Drop for Sender<'_, T, N> {
+    fn drop(&mut self) {
+        if self.channel.senders.remove() {
+            self.channel.close();
+        }
+    }
 }
 
-pub struct Receiver<'a, T> {
-    channel: &'a Channel<T>,
-    state: ReceiverState,
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
 }
 
-impl<T> Receiver<'_, T> {
-    pub async fn receive(&mut self) -> T {
+impl<T, const N: usize> Receiver<'_, T, N> {
+    /// Receive the oldest item, waiting until there is one
+    ///
+    /// Items already queued are returned on the first poll. Dropping the
+    /// future before it is ready, e.g. in `select_biased!`, loses no item.
+    /// Returns `Err(Closed)` once the channel is closed and all items are received.
+    pub async fn receive(&mut self) -> Result<T, Closed> {
         poll_fn(|cx: &mut Context| {
-            match self.state {
-                ReceiverState::Init => {
-                    self.channel.register(cx.waker().clone());
-                    self.state = ReceiverState::Wait;
-                    Poll::Pending
-                }
-                ReceiverState::Wait => {
-                    match self.channel.receive() {
-                        Some(item) => Poll::Ready(item),
-                        None => Poll::Pending,
-                    }
-                }
+            // Register first, so an item sent right after the check still wakes this task
+            self.channel.register(cx.waker());
+
+            match self.channel.has_item() {
+                Ok(true) => {}
+                Ok(false) => return Poll::Pending,
+                Err(closed) => return Poll::Ready(Err(closed)),
+            }
+
+            // Out of budget, leave the item for the next poll
+            if executor::poll_budget(cx).is_pending() {
+                return Poll::Pending;
+            }
 
+            match self.channel.receive() {
+                Some(item) => Poll::Ready(Ok(item)),
+                None => Poll::Pending,
             }
         }).await
     }
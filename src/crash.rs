@@ -0,0 +1,213 @@
+//! Crash reports that survive the reset
+//!
+//! The firmware's panic and HardFault handlers call `record_panic` and
+//! `record_hard_fault`, which record what happened in `.uninit` RAM that start up
+//! does not zero, then reset the chip. The next boot can pick the report up with
+//! `take_report`, even if no debugger was attached when it crashed. Reports
+//! hold the panic message, the task that was being polled and, for a
+//! HardFault, the registers the core stacked. Cortex-M0 has no fault status
+//! registers, so the stacked PC is the best hint where it went wrong.
+
+use core::{
+    fmt::{self, Write},
+    mem::MaybeUninit,
+    panic::PanicInfo,
+    ptr,
+};
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+use rtt_target::rprintln;
+
+use crate::executor::{self, Priority};
+
+// Constants
+const MESSAGE_LEN: usize = 128;
+const REPORT_MAGIC: u32 = 0x4352_5348; // "CRSH"
+const NO_TASK: u32 = u32::MAX;
+
+/// What caused the crash
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrashKind {
+    Panic,
+    HardFault,
+}
+
+/// Registers stacked by the core when the HardFault was taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FaultRegisters {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+/// Crash report left behind by the previous run
+#[derive(Clone, Copy, Debug)]
+pub struct CrashReport {
+    pub kind: CrashKind,
+    /// Executor and ID of the task that was being polled
+    pub task: Option<(Priority, usize)>,
+    /// Only recorded for a HardFault
+    pub registers: Option<FaultRegisters>,
+    message: [u8; MESSAGE_LEN],
+    message_len: usize,
+}
+
+impl CrashReport {
+    /// Panic message with its location, cut off after 128 bytes
+    pub fn message(&self) -> &str {
+        let message = &self.message[..self.message_len];
+        match core::str::from_utf8(message) {
+            Ok(message) => message,
+            // Only the end can be broken, the message is cut at char boundaries
+            Err(error) => core::str::from_utf8(&message[..error.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            CrashKind::Panic => write!(f, "Panic: {}", self.message())?,
+            CrashKind::HardFault => write!(f, "HardFault")?,
+        }
+
+        match self.task {
+            Some((priority, task_id)) => write!(f, "\n  while polling task {} on {:?} executor", task_id, priority)?,
+            None => write!(f, "\n  outside of any task")?,
+        }
+
+        if let Some(r) = self.registers {
+            write!(f, "\n  pc {:08x} lr {:08x} xpsr {:08x}", r.pc, r.lr, r.xpsr)?;
+            write!(f, "\n  r0 {:08x} r1 {:08x} r2 {:08x} r3 {:08x} r12 {:08x}", r.r0, r.r1, r.r2, r.r3, r.r12)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Layout in RAM, only valid if `magic` is REPORT_MAGIC
+#[repr(C)]
+struct SavedReport {
+    magic: u32,
+    kind: u32,
+    priority: u32,
+    task_id: u32,
+    registers: [u32; 8], // All zero after a panic
+    message_len: u32,
+    message: [u8; MESSAGE_LEN],
+}
+
+// Not zeroed on start up, so the report survives the reset
+#[unsafe(link_section = ".uninit.CRASH_REPORT")]
+static mut SAVED_REPORT: MaybeUninit<SavedReport> = MaybeUninit::uninit();
+
+/// Crash report of the previous run, cleared once read
+pub fn take_report() -> Option<CrashReport> {
+    let saved = (&raw mut SAVED_REPORT).cast::<SavedReport>();
+
+    // SAFETY: Only plain integers are read, any bit pattern is valid for them
+    let saved = unsafe {
+        if ptr::read_volatile(&raw const (*saved).magic) != REPORT_MAGIC {
+            return None;
+        }
+
+        ptr::write_volatile(&raw mut (*saved).magic, 0);
+        ptr::read_volatile(saved)
+    };
+
+    let priority = Priority::from_u32(saved.priority);
+    let [r0, r1, r2, r3, r12, lr, pc, xpsr] = saved.registers;
+
+    Some(CrashReport {
+        kind: if saved.kind == CrashKind::HardFault as u32 { CrashKind::HardFault } else { CrashKind::Panic },
+        task: (saved.task_id != NO_TASK).then_some((priority, saved.task_id as usize)),
+        registers: (saved.kind == CrashKind::HardFault as u32).then_some(FaultRegisters {
+            r0, r1, r2, r3, r12, lr, pc, xpsr,
+        }),
+        message: saved.message,
+        message_len: (saved.message_len as usize).min(MESSAGE_LEN),
+    })
+}
+
+/// Write the report for the next boot and reset
+fn save_and_reset(kind: CrashKind, registers: [u32; 8], message: fmt::Arguments) -> ! {
+    cortex_m::interrupt::disable();
+
+    let (priority, task_id) = match executor::current_task() {
+        Some((priority, task_id)) => (priority as u32, task_id as u32),
+        None => (0, NO_TASK),
+    };
+
+    let mut writer = MessageWriter {
+        message: [0; MESSAGE_LEN],
+        len: 0,
+    };
+    // Cannot fail, the writer cuts the message off instead
+    writer.write_fmt(message).ok();
+
+    let saved = (&raw mut SAVED_REPORT).cast::<SavedReport>();
+
+    // SAFETY: Interrupts are off and the report is not read again before the reset
+    unsafe {
+        ptr::write_volatile(saved, SavedReport {
+            magic: REPORT_MAGIC,
+            kind: kind as u32,
+            priority,
+            task_id,
+            registers,
+            message_len: writer.len as u32,
+            message: writer.message,
+        });
+    }
+
+    // Shows up if a debugger is attached, the report is there either way
+    rprintln!("{:?}, resetting: {}", kind, message);
+
+    SCB::sys_reset();
+}
+
+/// Collects a formatted message, cut off at a char boundary once full
+struct MessageWriter {
+    message: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Write for MessageWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(MESSAGE_LEN - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.message[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// Record a panic for the next boot and reset, call it from the `#[panic_handler]`
+pub fn record_panic(info: &PanicInfo) -> ! {
+    save_and_reset(CrashKind::Panic, [0; 8], format_args!("{}", info))
+}
+
+/// Record a HardFault with its stacked registers and reset, call it from the `HardFault` handler
+pub fn record_hard_fault(frame: &ExceptionFrame) -> ! {
+    let registers = [
+        frame.r0(),
+        frame.r1(),
+        frame.r2(),
+        frame.r3(),
+        frame.r12(),
+        frame.lr(),
+        frame.pc(),
+        frame.xpsr(),
+    ];
+
+    save_and_reset(CrashKind::HardFault, registers, format_args!(""))
+}
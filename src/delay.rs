@@ -0,0 +1,89 @@
+use embedded_hal::delay::DelayNs as BlockingDelayNs;
+use embedded_hal_async::delay::DelayNs;
+use fugit::TimerDuration;
+
+use crate::ticker::{TickInstant, TickTimer, Ticker, TICK_HZ};
+
+// Units of the DelayNs methods in Hz
+const NANOS_HZ: u64 = 1_000_000_000;
+const MICROS_HZ: u64 = 1_000_000;
+const MILLIS_HZ: u64 = 1_000;
+
+/// `DelayNs` handle for third-party drivers, backed by the ticker
+///
+/// The async version waits on a `TickTimer`, so other tasks keep running.
+/// The blocking version spins on `Ticker::now()` and only needs the time
+/// driver, so it also works before the executor is started. With the SysTick
+/// driver it must not be called with interrupts disabled, since SysTick time
+/// only advances in its interrupt handler.
+///
+/// Delays are at least as long as requested. A delay is rounded up to whole
+/// ticks, plus one tick because the current tick is already partly over.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TickerDelay;
+
+impl TickerDelay {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl DelayNs for TickerDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        wait_until(deadline_after(ns as u64, NANOS_HZ)).await;
+    }
+
+    async fn delay_us(&mut self, us: u32) {
+        wait_until(deadline_after(us as u64, MICROS_HZ)).await;
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        wait_until(deadline_after(ms as u64, MILLIS_HZ)).await;
+    }
+}
+
+impl BlockingDelayNs for TickerDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        spin_until(deadline_after(ns as u64, NANOS_HZ));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        spin_until(deadline_after(us as u64, MICROS_HZ));
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        spin_until(deadline_after(ms as u64, MILLIS_HZ));
+    }
+}
+
+/// Earliest instant that is at least `amount` units of `unit_hz` from now
+fn deadline_after(amount: u64, unit_hz: u64) -> Option<TickInstant> {
+    if amount == 0 {
+        return None;
+    }
+
+    // Cannot overflow: u32::MAX * 1 MHz (highest tick rate) fits easily in u64
+    let ticks = (amount * TICK_HZ as u64).div_ceil(unit_hz) + 1;
+    Some(Ticker::now() + TimerDuration::<u64, TICK_HZ>::from_ticks(ticks))
+}
+
+async fn wait_until(deadline: Option<TickInstant>) {
+    if let Some(deadline) = deadline {
+        TickTimer::at(deadline).await;
+    }
+}
+
+fn spin_until(deadline: Option<TickInstant>) {
+    let Some(deadline) = deadline else {
+        return;
+    };
+
+    while Ticker::now() < deadline {
+        // Virtual time only moves when told to
+        #[cfg(feature = "std")]
+        crate::time_driver::MockDriver::advance_to(deadline.ticks());
+
+        #[cfg(not(feature = "std"))]
+        core::hint::spin_loop();
+    }
+}
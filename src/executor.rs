@@ -1,39 +1,84 @@
-use cortex_m::asm;
 use core::sync::atomic::Ordering;
-use heapless::mpmc::Queue;
+use critical_section::{CriticalSection, Mutex};
+use heapless::Deque;
+use portable_atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicUsize};
 use rtt_target::rprintln;
+#[cfg(feature = "interrupt-executors")]
+use stm32f0xx_hal::pac::{interrupt, Interrupt};
+
+use crate::ticker::Ticker;
 use core::{
-    future::Future,
+    cell::{Cell, RefCell, UnsafeCell},
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    mem::{align_of, size_of, MaybeUninit},
     pin::Pin,
-    task::{Context, RawWaker, RawWakerVTable, Waker},
-    sync::atomic::AtomicUsize,
+    ptr,
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
 };
 
+mod stats;
+
+pub use stats::{cpu_usage, print_stats, CpuUsage, TaskStats};
+#[cfg(feature = "std")]
+pub(crate) use stats::record_idle;
+
+// Constants
+const MAX_TASKS: usize = 4;          // Tasks passed to run_tasks
+const MAX_SPAWNED_TASKS: usize = 4;  // Tasks started at runtime through a Spawner
+const TASK_STORAGE_SIZE: usize = 256; // Bytes reserved per spawned future
+const SPAWN_POOL_SLOTS: usize = 2;     // Futures passed to Spawner::spawn, #[task] brings its own pools
+pub(crate) const TOTAL_TASKS: usize = MAX_TASKS + MAX_SPAWNED_TASKS;
+const READY_QUEUE_SIZE: usize = TOTAL_TASKS; // Every task fits in at once
+const NO_TASK: usize = 0; // Task ID plus one is stored while polling
+const UNLIMITED_BUDGET: u16 = u16::MAX;
+
+// Waker data layout: executor address with the task ID in its low bits
+const TASK_ID_MASK: usize = align_of::<Executor>() - 1;
+const _: () = assert!(TOTAL_TASKS <= align_of::<Executor>(), "Task IDs don't fit into the executor alignment");
+
+type TaskRef = Pin<&'static mut dyn Future<Output = ()>>;
+
 pub trait ExtWaker {
-    fn task_id(&self) -> usize;
+    /// ID of the task this waker belongs to, None for wakers from other executors
+    fn task_id(&self) -> Option<usize>;
+
+    /// Executor and ID of the task this waker belongs to, task IDs restart on each executor
+    fn task(&self) -> Option<(Priority, usize)>;
 }
 
 impl ExtWaker for Waker {
-    fn task_id(&self) -> usize {
-        //return (self.as_raw().data() as usize);
-
-        for task_id in 0..NUM_TASKS.load(Ordering::Relaxed) {
-            if get_waker(task_id).will_wake(self) {
-                return task_id;
-            }
+    fn task_id(&self) -> Option<usize> {
+        // Our wakers carry the executor and task ID as their data pointer
+        if ptr::eq(self.vtable(), &VTABLE) {
+            Some(self.data() as usize & TASK_ID_MASK)
+        } else {
+            None
         }
-        panic!("Unknown waker/executor!");
     }
-}
 
-fn get_waker(task_id: usize) -> Waker {
-    // SAFETY:
-    // Data argument interpreted as an integer, not dereferenced
-    unsafe {
-        Waker::from_raw(RawWaker::new(task_id as *const (), &VTABLE))
+    fn task(&self) -> Option<(Priority, usize)> {
+        decode_waker(self).map(|(executor, task_id)| (executor.priority(), task_id))
     }
 }
 
+/// Executor and task ID behind one of our wakers
+fn decode_waker(waker: &Waker) -> Option<(&'static Executor, usize)> {
+    let task_id = waker.task_id()?;
+
+    // SAFETY: Wakers are only created for executors in statics
+    let executor = unsafe { &*((waker.data() as usize & !TASK_ID_MASK) as *const Executor) };
+    Some((executor, task_id))
+}
+
+fn wake_from_data(p: *const ()) {
+    let data = p as usize;
+
+    // SAFETY: Wakers are only created for executors in statics
+    let executor = unsafe { &*((data & !TASK_ID_MASK) as *const Executor) };
+    executor.wake(data & TASK_ID_MASK);
+}
+
 static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
 
 unsafe fn clone(p: *const ()) -> RawWaker {
@@ -43,48 +88,977 @@ unsafe fn clone(p: *const ()) -> RawWaker {
 unsafe fn drop(_p: *const ()) {}
 
 unsafe fn wake(p: *const ()) {
-    wake_task(p as usize);
+    wake_from_data(p);
 }
 unsafe fn wake_by_ref(p: *const ()) {
-    wake_task(p as usize);
+    wake_from_data(p);
 }
 
-static NUM_TASKS: AtomicUsize = AtomicUsize::new(0);
-static TASK_IS_READY: Queue<usize, 4> = Queue::new();
+/// Executor a task runs on
+///
+/// `Thread` is the executor started by `run_tasks`. The others poll their
+/// tasks from otherwise unused interrupts, so their tasks preempt those of
+/// all lower priorities. The TIM2 and EXTI interrupts stay above all of them.
+/// The interrupt executors need the `interrupt-executors` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Thread-mode executor, runs whenever no interrupt is active
+    Thread,
+    /// Runs from the TSC interrupt
+    Medium,
+    /// Runs from the CEC_CAN interrupt
+    High,
+}
 
-pub fn wake_task(task_id: usize) {
-    rprintln!("Waking task {}", task_id);
+impl Priority {
+    /// Priority saved as `priority as u32`, e.g. in a report kept across a reset
+    pub(crate) fn from_u32(value: u32) -> Self {
+        match value {
+            0 => Priority::Thread,
+            1 => Priority::Medium,
+            _ => Priority::High,
+        }
+    }
 
-    if TASK_IS_READY.enqueue(task_id).is_err() {
-        panic!("Task queue full: can't add task {}", task_id);
+    fn name(self) -> &'static str {
+        match self {
+            Priority::Thread => "Thread",
+            Priority::Medium => "Medium",
+            Priority::High => "High",
+        }
+    }
+
+    /// Interrupt the executor runs from, None for thread mode
+    #[cfg(feature = "interrupt-executors")]
+    fn interrupt(self) -> Option<Interrupt> {
+        match self {
+            Priority::Thread => None,
+            Priority::Medium => Some(Interrupt::TSC),
+            Priority::High => Some(Interrupt::CEC_CAN),
+        }
+    }
+
+    /// NVIC priority of the interrupt, Cortex-M0 only implements the top two bits
+    #[cfg(all(feature = "interrupt-executors", not(feature = "std")))]
+    fn nvic_priority(self) -> u8 {
+        match self {
+            Priority::Thread => 0xC0,
+            Priority::Medium => 0x80,
+            Priority::High => 0x40,
+        }
     }
 }
 
-pub fn run_tasks(tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) -> ! {
-    NUM_TASKS.store(tasks.len(), Ordering::Relaxed);
+/// Lifecycle of a task slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum TaskState {
+    /// Queued to be polled
+    Ready = 1,
+    /// Polled and waiting to be woken
+    Pending = 2,
+    /// Completed, or no task in this slot. Never polled.
+    Done = 0, // Zero, so an executor starts out all zeroes
+}
+
+impl TaskState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => TaskState::Ready,
+            2 => TaskState::Pending,
+            _ => TaskState::Done,
+        }
+    }
+}
+
+// All zeroes, so they go to .bss and take no flash
+pub(crate) static THREAD_EXECUTOR: Executor = Executor::new();
+#[cfg(feature = "interrupt-executors")]
+static MEDIUM_EXECUTOR: Executor = Executor::new();
+#[cfg(feature = "interrupt-executors")]
+static HIGH_EXECUTOR: Executor = Executor::new();
 
-    // Initially wake all tasks to let them register their first deadlines
-    for task_id in 0..tasks.len() {
-        TASK_IS_READY.enqueue(task_id).ok();
+/// Built-in executor of `priority`, None for interrupt executors not built in
+fn builtin_executor(priority: Priority) -> Option<&'static Executor> {
+    match priority {
+        Priority::Thread => Some(&THREAD_EXECUTOR),
+        #[cfg(feature = "interrupt-executors")]
+        Priority::Medium => Some(&MEDIUM_EXECUTOR),
+        #[cfg(feature = "interrupt-executors")]
+        Priority::High => Some(&HIGH_EXECUTOR),
+        #[cfg(not(feature = "interrupt-executors"))]
+        Priority::Medium | Priority::High => None,
+    }
+}
+
+/// Built-in executors from the lowest priority to the highest
+fn builtin_executors() -> impl DoubleEndedIterator<Item = (Priority, &'static Executor)> {
+    [Priority::Thread, Priority::Medium, Priority::High]
+        .into_iter()
+        .filter_map(|priority| Some((priority, builtin_executor(priority)?)))
+}
+
+/// Task table, ready queue and task pool of one executor
+///
+/// Wakers point back to the executor their task belongs to, so an executor
+/// has to live in a `static`. `run_tasks` and `spawner` use a built-in
+/// thread-mode executor, the interrupt executors are selected by `Priority`.
+#[repr(align(8))] // Leaves the low address bits for the task ID in wakers
+pub struct Executor {
+    states: [AtomicU8; TOTAL_TASKS],
+    ready: Mutex<RefCell<Deque<usize, READY_QUEUE_SIZE>>>,
+    // Set while a task ID is in `ready`, so repeated wakes queue it only once
+    queued: [AtomicBool; TOTAL_TASKS],
+    tasks: TaskTable,
+    // Storage for futures started with Spawner::spawn
+    pool: TaskPool<SPAWN_POOL_SLOTS, TASK_STORAGE_SIZE>,
+    stats: stats::StatsTable,
+    // Task being polled right now plus one, NO_TASK in between
+    polling: AtomicUsize,
+    // Ready futures each task may await per poll, and what is left of it in the current poll.
+    // Both are set before they are used, when a task begins and before each poll.
+    budgets: [AtomicU16; TOTAL_TASKS],
+    budget_left: AtomicU16,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    /// Executor for thread mode, started with `run` or `run_to_completion`
+    pub const fn new() -> Self {
+        Self {
+            states: [const { AtomicU8::new(TaskState::Done as u8) }; TOTAL_TASKS],
+            ready: Mutex::new(RefCell::new(Deque::new())),
+            queued: [const { AtomicBool::new(false) }; TOTAL_TASKS],
+            tasks: TaskTable::new(),
+            pool: TaskPool::new(),
+            stats: stats::StatsTable::new(),
+            polling: AtomicUsize::new(NO_TASK),
+            budgets: [const { AtomicU16::new(0) }; TOTAL_TASKS],
+            budget_left: AtomicU16::new(0),
+        }
     }
 
-    loop {
-        while let Some(task_id) = TASK_IS_READY.dequeue() {
-            if task_id >= tasks.len() {
-                rprintln!("Bad task id {}!", task_id);
-                continue;
+    /// Which built-in executor this is, thread mode for any other
+    fn priority(&self) -> Priority {
+        builtin_executors()
+            .find(|(_, executor)| ptr::eq(self, *executor))
+            .map_or(Priority::Thread, |(priority, _)| priority)
+    }
+
+    /// Get a spawner for this executor
+    pub fn spawner(&'static self) -> Spawner {
+        Spawner { executor: self }
+    }
+
+    /// Poll the tasks, and those spawned later, forever
+    pub fn run(&'static self, tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) -> ! {
+        self.start(tasks);
+
+        loop {
+            self.run_ready(tasks);
+
+            // Enter sleep mode - processor will wake on any interrupt
+            // (timer compare, button interrupt, etc.)
+            rprintln!("Entering sleep mode...");
+            self.sleep();
+            rprintln!("Woke from sleep");
+        }
+    }
+
+    /// Like `run`, but returns once every task, spawned ones included, has completed
+    ///
+    /// Tasks spawned on the interrupt executors count too, as they run on top.
+    pub fn run_to_completion(&'static self, tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) {
+        self.start(tasks);
+
+        loop {
+            self.run_ready(tasks);
+
+            if self.all_tasks_done(tasks.len())
+                && builtin_executors().all(|(priority, executor)| priority == Priority::Thread || executor.all_tasks_done(0))
+            {
+                rprintln!("All tasks completed");
+                return;
             }
 
+            rprintln!("Entering sleep mode...");
+            self.sleep();
+            rprintln!("Woke from sleep");
+        }
+    }
+
+    /// Forget all tasks and drop the spawned ones, so the executor starts from scratch
+    ///
+    /// Lets host tests run several scenarios in one process. Must not be
+    /// called while the executor runs. JoinHandles of the dropped tasks are
+    /// left behind, awaiting one of them panics.
+    pub fn reset(&self) {
+        critical_section::with(|cs| self.ready.borrow(cs).borrow_mut().clear());
+        self.polling.store(NO_TASK, Ordering::Relaxed);
+
+        for task_id in 0..TOTAL_TASKS {
+            self.set_task_state(task_id, TaskState::Done);
+            self.queued[task_id].store(false, Ordering::Release);
+            self.budgets[task_id].store(UNLIMITED_BUDGET, Ordering::Relaxed);
+        }
+
+        for (slot, task_slot) in self.tasks.slots.iter().enumerate() {
+            if task_slot.used.load(Ordering::Acquire) {
+                self.tasks.release(slot);
+            }
+        }
+
+        self.stats.reset();
+    }
+
+    /// Runtime statistics of a task, None if there is no such task ID
+    ///
+    /// IDs below 4 are the tasks passed to `run`, in order, spawned tasks
+    /// come after them. Statistics are kept when a task completes, and
+    /// continue when its slot is reused.
+    pub fn task_stats(&self, task_id: usize) -> Option<TaskStats> {
+        (task_id < TOTAL_TASKS).then(|| self.stats.get(task_id))
+    }
+
+    fn task_state(&self, task_id: usize) -> TaskState {
+        TaskState::from_u8(self.states[task_id].load(Ordering::Acquire))
+    }
+
+    fn set_task_state(&self, task_id: usize, state: TaskState) {
+        self.states[task_id].store(state as u8, Ordering::Release);
+    }
+
+    fn waker(&'static self, task_id: usize) -> Waker {
+        let data = ptr::from_ref(self) as usize | task_id;
+
+        // SAFETY:
+        // Data argument only dereferenced as the static executor it points to
+        unsafe {
+            Waker::from_raw(RawWaker::new(data as *const (), &VTABLE))
+        }
+    }
+
+    fn wake(&self, task_id: usize) {
+        // One step, so a task completed by a preempting executor stays Done
+        let woken = task_id < TOTAL_TASKS
+            && match self.states[task_id].compare_exchange(
+                TaskState::Pending as u8,
+                TaskState::Ready as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => true,
+                Err(state) => TaskState::from_u8(state) == TaskState::Ready,
+            };
+        if !woken {
+            rprintln!("Ignoring wake for finished task {}", task_id);
+            return;
+        }
+
+        rprintln!("Waking task {}", task_id);
+        self.stats.record_wake(task_id);
+
+        if self.queued[task_id].swap(true, Ordering::AcqRel) {
+            // Still queued from an earlier wake, it runs only once
+            return;
+        }
+
+        if critical_section::with(|cs| self.ready.borrow(cs).borrow_mut().push_back(task_id)).is_err() {
+            unreachable!("Ready queue holds every task, task {} can't be queued twice", task_id);
+        }
+
+        // Interrupt executors run as soon as their interrupt is allowed to. In
+        // simulation they run between thread-mode polls instead.
+        #[cfg(all(feature = "interrupt-executors", not(feature = "std")))]
+        if let Some(interrupt) = self.priority().interrupt() {
+            cortex_m::peripheral::NVIC::pend(interrupt);
+        }
+    }
+
+    /// Register the tasks and mark all of them ready for their first poll
+    pub(crate) fn start(&self, tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) {
+        assert!(tasks.len() <= MAX_TASKS, "Too many tasks: at most {} supported", MAX_TASKS);
+
+        // Initially wake all tasks to let them register their first deadlines
+        for task_id in 0..tasks.len() {
+            self.begin_task(task_id);
+        }
+    }
+
+    /// Queue a new task for its first poll, without a poll budget
+    fn begin_task(&self, task_id: usize) {
+        self.budgets[task_id].store(UNLIMITED_BUDGET, Ordering::Relaxed);
+        self.set_task_state(task_id, TaskState::Pending);
+        self.wake(task_id);
+    }
+
+    /// Poll ready tasks until the ready queue is empty
+    pub(crate) fn run_ready(&'static self, tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) {
+        loop {
+            #[cfg(feature = "std")]
+            run_interrupt_executors();
+
+            if !self.poll_next_task(tasks) {
+                break;
+            }
+        }
+    }
+
+    /// Poll the next ready task, returns false if none was ready
+    ///
+    /// `tasks` are the tasks passed to `run`, empty for interrupt executors.
+    fn poll_next_task(&'static self, tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) -> bool {
+        let Some(task_id) = critical_section::with(|cs| self.ready.borrow(cs).borrow_mut().pop_front()) else {
+            return false;
+        };
+
+        self.queued[task_id].store(false, Ordering::Release);
+
+        // A completed task may still have had a wake queued
+        if self.task_state(task_id) != TaskState::Ready {
+            return true;
+        }
+
+        // Set before polling, so a wake during the poll queues the task again
+        self.set_task_state(task_id, TaskState::Pending);
+
+        let waker = self.waker(task_id);
+        let mut cx = Context::from_waker(&waker);
+        let start = Ticker::now();
+        self.polling.store(task_id + 1, Ordering::Relaxed);
+        self.budget_left.store(self.budgets[task_id].load(Ordering::Relaxed), Ordering::Relaxed);
+
+        let result = if task_id < tasks.len() {
             rprintln!("Running task {}", task_id);
-            let _ = tasks[task_id]
-                .as_mut()
-                .poll(&mut Context::from_waker(&get_waker(task_id)));
+            tasks[task_id].as_mut().poll(&mut cx)
+        } else if (MAX_TASKS..TOTAL_TASKS).contains(&task_id) {
+            rprintln!("Running spawned task {} on {:?} executor", task_id, self.priority());
+            self.tasks.poll(task_id - MAX_TASKS, &mut cx)
+        } else {
+            rprintln!("Bad task id {}!", task_id);
+            self.polling.store(NO_TASK, Ordering::Relaxed);
+            return true;
+        };
+        self.polling.store(NO_TASK, Ordering::Relaxed);
+
+        self.stats.record_poll(task_id, start, Ticker::now());
+
+        if result.is_ready() {
+            rprintln!("Task {} completed", task_id);
+
+            // Done and handed to the JoinHandle in one step, so only one side releases the slot
+            let slot = task_id.checked_sub(MAX_TASKS);
+            let (release, join_waker) = critical_section::with(|cs| {
+                self.set_task_state(task_id, TaskState::Done);
+                slot.map_or((false, None), |slot| self.tasks.complete(cs, slot))
+            });
+
+            if let Some(waker) = join_waker {
+                waker.wake();
+            }
+            if release && let Some(slot) = slot {
+                self.tasks.release(slot);
+            }
+        }
+
+        true
+    }
+
+    /// Drop the JoinHandle's claim on `slot`, returns true if the task is already done
+    ///
+    /// Done in one step with the completion, so only one side releases the slot.
+    fn detach(&self, slot: usize) -> bool {
+        critical_section::with(|_| {
+            self.tasks.slots[slot].joined.store(false, Ordering::Release);
+            self.task_state(MAX_TASKS + slot) == TaskState::Done
+        })
+    }
+
+    /// True if all tasks from the slice and all spawned tasks have completed
+    ///
+    /// Spawned tasks whose output is still waiting for its JoinHandle count as done.
+    fn all_tasks_done(&self, num_tasks: usize) -> bool {
+        (0..num_tasks)
+            .chain(MAX_TASKS..TOTAL_TASKS)
+            .all(|task_id| self.task_state(task_id) == TaskState::Done)
+    }
+
+    /// Wait for the next interrupt, the time is counted as idle
+    fn sleep(&self) {
+        let start = Ticker::now();
+
+        // Stop mode if the next deadline is far away, unless a task got woken meanwhile
+        #[cfg(not(feature = "std"))]
+        crate::power::sleep(|| self.queued.iter().any(|queued| queued.load(Ordering::Acquire)));
+
+        // In simulation there are no interrupts to wait for, so jump ahead to the next event
+        #[cfg(feature = "std")]
+        crate::sim::idle();
+
+        stats::record_idle(start, Ticker::now());
+    }
+}
+
+/// Error returned by `Spawner::spawn` and `Spawner::spawn_task`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpawnError {
+    /// All task pool slots are in use
+    PoolExhausted,
+    /// The executor already runs as many spawned tasks as it can
+    TooManyTasks,
+}
+
+/// Handle for starting tasks on one of the executors
+#[derive(Clone, Copy)]
+pub struct Spawner {
+    executor: &'static Executor,
+}
+
+impl Spawner {
+    /// Start `future` as a new task, it is first polled on the next executor pass
+    ///
+    /// The future is moved into a statically allocated pool slot, so it must
+    /// not borrow anything that does not live forever. Futures larger than
+    /// the slot size are rejected at compile time. The returned handle can be
+    /// awaited for the task's output, or dropped to let the task run detached.
+    pub fn spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_task(self.executor.pool.claim(future))
+    }
+
+    /// Start a task created by a `#[task]` function
+    pub fn spawn_task<T>(&self, mut token: SpawnToken<T>) -> Result<JoinHandle<T>, SpawnError> {
+        let task = token.task.take().ok_or(SpawnError::PoolExhausted)?;
+
+        let executor = self.executor;
+        let slot = executor.tasks.insert(task)?;
+        let generation = executor.tasks.slots[slot].generation.load(Ordering::Acquire);
+        let task_id = MAX_TASKS + slot;
+
+        rprintln!("Spawned task {} on {:?} executor", task_id, executor.priority());
+        executor.begin_task(task_id);
+
+        Ok(JoinHandle {
+            executor,
+            slot: Some(slot),
+            generation,
+            _output: PhantomData,
+        })
+    }
+}
+
+/// Get a spawner for the thread-mode executor
+pub fn spawner() -> Spawner {
+    THREAD_EXECUTOR.spawner()
+}
+
+/// Enable the interrupt of a higher-priority executor and get its spawner
+///
+/// Tasks spawned on it are polled from its interrupt as soon as they are
+/// woken, preempting thread-mode tasks at any point. Anything they share with
+/// lower-priority tasks must be protected by a critical section.
+#[cfg(feature = "interrupt-executors")]
+pub fn start_interrupt_executor(priority: Priority) -> Spawner {
+    let interrupt = priority.interrupt().expect("Thread mode has no interrupt executor");
+    let executor = builtin_executor(priority).expect("Interrupt executors are built in");
+
+    #[cfg(not(feature = "std"))]
+    // SAFETY: Only the executor's own interrupt is configured, the executor
+    // handles being entered at any time
+    unsafe {
+        let mut nvic = cortex_m::Peripherals::steal().NVIC;
+        nvic.set_priority(interrupt, priority.nvic_priority());
+        cortex_m::peripheral::NVIC::unpend(interrupt);
+        cortex_m::peripheral::NVIC::unmask(interrupt);
+    }
+
+    rprintln!("{:?} executor started on {:?}", priority, interrupt);
+    executor.spawner()
+}
+
+/// Awaitable output of a spawned task
+///
+/// Dropping the handle detaches the task: it keeps running and its output
+/// is dropped when it completes.
+pub struct JoinHandle<T> {
+    executor: &'static Executor,
+    // None once the output was taken
+    slot: Option<usize>,
+    // Generation of the slot when the task was spawned, it changes once the slot is released
+    generation: u32,
+    _output: PhantomData<T>,
+}
+
+impl<T> JoinHandle<T> {
+    /// True once the task has completed
+    pub fn is_finished(&self) -> bool {
+        match self.task_slot() {
+            Some(slot) => self.executor.task_state(MAX_TASKS + slot) == TaskState::Done,
+            None => true,
+        }
+    }
+
+    /// Slot of the task, None once the output was taken or the executor was reset
+    fn task_slot(&self) -> Option<usize> {
+        let slot = self.slot?;
+        (self.executor.tasks.slots[slot].generation.load(Ordering::Acquire) == self.generation).then_some(slot)
+    }
+}
+
+// The output is stored with the task, not in the handle
+impl<T> Unpin for JoinHandle<T> {}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        assert!(self.slot.is_some(), "JoinHandle polled after completion");
+        // The slot may hold another task by now, its storage is not our output
+        let slot = self.task_slot().expect("JoinHandle of a task dropped by Executor::reset");
+        let tasks = &self.executor.tasks;
+
+        // Register first, so a completion right after the check still wakes us
+        tasks.slots[slot].set_join_waker(cx.waker());
+        if self.executor.task_state(MAX_TASKS + slot) != TaskState::Done {
+            return Poll::Pending;
+        }
+
+        // SAFETY: The slot holds a JoinableTask whose output type is T, and
+        // repr(C) puts the output at the start of it
+        let output = unsafe {
+            let output = tasks.slots[slot].task_ptr().cast::<Option<T>>();
+            (*output).take().expect("Task output already taken")
+        };
+
+        self.slot = None;
+        tasks.release(slot);
+        Poll::Ready(output)
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.task_slot() {
+            // Free the slot now if the task is done, otherwise once it completes
+            if self.executor.detach(slot) {
+                self.executor.tasks.release(slot);
+            }
+        }
+    }
+}
+
+/// Task whose future is stored in a task pool, ready to be spawned
+///
+/// Returned by `#[task]` functions. Dropping it drops the future unpolled.
+#[must_use = "Tasks do nothing unless spawned"]
+pub struct SpawnToken<T> {
+    // None if the pool was exhausted
+    task: Option<ClaimedTask>,
+    _output: PhantomData<T>,
+}
+
+impl<T> Drop for SpawnToken<T> {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.release();
+        }
+    }
+}
+
+/// Spawned future together with the place its output is kept for the JoinHandle
+#[repr(C)]
+struct JoinableTask<F: Future> {
+    // Must stay the first field, the JoinHandle reads it without knowing F
+    output: Option<F::Output>,
+    future: Option<F>,
+}
+
+impl<F: Future> Future for JoinableTask<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // SAFETY: `future` is structurally pinned and never moved out, only
+        // dropped in place. `output` is not pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        let Some(inner) = future.as_mut().as_pin_mut() else {
+            return Poll::Ready(());
+        };
+
+        match inner.poll(cx) {
+            Poll::Ready(output) => {
+                this.output = Some(output);
+                // Drop the finished future right away, only the output is kept
+                future.set(None);
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Async function whose future size `task_size` can tell, used by `#[task]`
+#[doc(hidden)]
+pub trait TaskFn<Args> {
+    type Fut: Future;
+}
+
+macro_rules! task_fn_impl {
+    ($($arg:ident),*) => {
+        impl<F, Fut, $($arg),*> TaskFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Fut,
+            Fut: Future,
+        {
+            type Fut = Fut;
         }
+    };
+}
+
+task_fn_impl!();
+task_fn_impl!(A);
+task_fn_impl!(A, B);
+task_fn_impl!(A, B, C);
+task_fn_impl!(A, B, C, D);
+task_fn_impl!(A, B, C, D, E);
+task_fn_impl!(A, B, C, D, E, G);
+task_fn_impl!(A, B, C, D, E, G, H);
+task_fn_impl!(A, B, C, D, E, G, H, I);
+
+/// Bytes a task pool slot needs for the future of `task_fn`, used by `#[task]`
+#[doc(hidden)]
+pub const fn task_size<F: TaskFn<Args>, Args>(_task_fn: &F) -> usize {
+    size_of::<JoinableTask<F::Fut>>()
+}
+
+// Storage for one spawned future, aligned for anything a future may contain
+#[repr(C, align(8))]
+struct TaskStorage<const SIZE: usize>([MaybeUninit<u8>; SIZE]);
+
+struct StorageSlot<const SIZE: usize> {
+    used: AtomicBool,
+    storage: UnsafeCell<TaskStorage<SIZE>>,
+}
 
-        // Enter sleep mode - processor will wake on any interrupt
-        // (timer compare, button interrupt, etc.)
-        rprintln!("Entering sleep mode...");
-        asm::wfi();
-        rprintln!("Woke from sleep");
+/// Statically allocated storage for `SLOTS` futures of up to `SIZE` bytes
+///
+/// Each executor has one for `Spawner::spawn`. The `#[task]` attribute
+/// generates one per task, sized for exactly that task's future.
+pub struct TaskPool<const SLOTS: usize, const SIZE: usize> {
+    slots: [StorageSlot<SIZE>; SLOTS],
+}
+
+// SAFETY: `storage` is only written by the caller that claimed the slot
+// through `used`, after that it is only accessed through the ClaimedTask.
+unsafe impl<const SLOTS: usize, const SIZE: usize> Sync for TaskPool<SLOTS, SIZE> {}
+
+impl<const SLOTS: usize, const SIZE: usize> Default for TaskPool<SLOTS, SIZE> {
+    fn default() -> Self {
+        Self::new()
     }
 }
+
+impl<const SLOTS: usize, const SIZE: usize> TaskPool<SLOTS, SIZE> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const {
+                StorageSlot {
+                    used: AtomicBool::new(false),
+                    storage: UnsafeCell::new(TaskStorage([MaybeUninit::uninit(); SIZE])),
+                }
+            }; SLOTS],
+        }
+    }
+
+    /// Move `future` into a free slot, to be started with `Spawner::spawn_task`
+    pub fn claim<F>(&'static self, future: F) -> SpawnToken<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        const {
+            assert!(size_of::<JoinableTask<F>>() <= SIZE, "Future too large for task pool slot");
+            assert!(align_of::<JoinableTask<F>>() <= align_of::<TaskStorage<SIZE>>(), "Future alignment too large for task pool slot");
+        }
+
+        let task = self.slots
+            .iter()
+            .find(|slot| !slot.used.swap(true, Ordering::Acquire))
+            .map(|slot| {
+                // SAFETY: The slot was free and is now ours, size and alignment
+                // were checked above. The storage is static and never moved, so
+                // the future stays pinned for as long as it lives.
+                let future: TaskRef = unsafe {
+                    let storage = slot.storage.get() as *mut JoinableTask<F>;
+                    storage.write(JoinableTask { output: None, future: Some(future) });
+                    Pin::new_unchecked(&mut *storage)
+                };
+
+                ClaimedTask { future, storage_used: &slot.used }
+            });
+
+        SpawnToken { task, _output: PhantomData }
+    }
+}
+
+// Future in a task pool slot, with the flag that frees the slot again
+struct ClaimedTask {
+    future: TaskRef,
+    storage_used: &'static AtomicBool,
+}
+
+impl ClaimedTask {
+    /// Drop what is left of the future and make its pool slot free again
+    fn release(self) {
+        // SAFETY: The task is never polled again, so nothing refers to its
+        // storage anymore
+        unsafe { ptr::drop_in_place(Pin::into_inner_unchecked(self.future) as *mut dyn Future<Output = ()>) };
+        self.storage_used.store(false, Ordering::Release);
+    }
+}
+
+struct TaskSlot {
+    used: AtomicBool,
+    // Taken out while the task is being polled, so it can spawn other tasks
+    task: Mutex<Cell<Option<ClaimedTask>>>,
+    // Whether a JoinHandle still refers to this slot
+    joined: AtomicBool,
+    join_waker: Mutex<RefCell<Option<Waker>>>,
+    // Counts releases, so a JoinHandle can tell its task still owns the slot
+    generation: AtomicU32,
+}
+
+// SAFETY: The task is only polled by the executor owning the slot, its
+// output is only read by the JoinHandle once the task is done.
+unsafe impl Sync for TaskSlot {}
+
+impl TaskSlot {
+    fn set_join_waker(&self, waker: &Waker) {
+        critical_section::with(|cs| {
+            let mut join_waker = self.join_waker.borrow(cs).borrow_mut();
+            if !join_waker.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                *join_waker = Some(waker.clone());
+            }
+        });
+    }
+
+    /// Start of the JoinableTask in this slot
+    fn task_ptr(&self) -> *mut () {
+        critical_section::with(|cs| {
+            let mut task = self.task.borrow(cs).take().expect("No task in slot");
+            // SAFETY: Only the address is taken, the future is not moved
+            let ptr = unsafe { task.future.as_mut().get_unchecked_mut() as *mut dyn Future<Output = ()> };
+            self.task.borrow(cs).set(Some(task));
+            ptr.cast()
+        })
+    }
+}
+
+/// Spawned tasks of an executor, referring to their futures in task pools
+struct TaskTable {
+    slots: [TaskSlot; MAX_SPAWNED_TASKS],
+}
+
+impl TaskTable {
+    const fn new() -> Self {
+        Self {
+            slots: [const {
+                TaskSlot {
+                    used: AtomicBool::new(false),
+                    task: Mutex::new(Cell::new(None)),
+                    joined: AtomicBool::new(false),
+                    join_waker: Mutex::new(RefCell::new(None)),
+                    generation: AtomicU32::new(0),
+                }
+            }; MAX_SPAWNED_TASKS],
+        }
+    }
+
+    /// Add a task to a free slot, returns the slot index
+    fn insert(&self, task: ClaimedTask) -> Result<usize, SpawnError> {
+        let Some((index, slot)) = self.slots
+            .iter()
+            .enumerate()
+            .find(|(_, slot)| !slot.used.swap(true, Ordering::Acquire))
+        else {
+            task.release();
+            return Err(SpawnError::TooManyTasks);
+        };
+
+        slot.joined.store(true, Ordering::Release);
+        critical_section::with(|cs| slot.task.borrow(cs).set(Some(task)));
+        Ok(index)
+    }
+
+    /// Poll the task in `slot`, if there is one
+    fn poll(&self, slot: usize, cx: &mut Context<'_>) -> Poll<()> {
+        let slot = &self.slots[slot];
+
+        match critical_section::with(|cs| slot.task.borrow(cs).take()) {
+            Some(mut task) => {
+                let result = task.future.as_mut().poll(cx);
+                critical_section::with(|cs| slot.task.borrow(cs).set(Some(task)));
+                result
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    /// Called once the task in `slot` completed, returns true if nobody waits
+    /// for the output, and the JoinHandle's waker
+    fn complete(&self, cs: CriticalSection, slot: usize) -> (bool, Option<Waker>) {
+        let slot = &self.slots[slot];
+        let join_waker = slot.join_waker.borrow(cs).borrow_mut().take();
+        (!slot.joined.load(Ordering::Acquire), join_waker)
+    }
+
+    /// Drop what is left of the task in `slot` and make the slot free again
+    fn release(&self, slot: usize) {
+        let slot = &self.slots[slot];
+
+        if let Some(task) = critical_section::with(|cs| slot.task.borrow(cs).take()) {
+            task.release();
+        }
+
+        critical_section::with(|cs| slot.join_waker.borrow(cs).replace(None));
+        slot.generation.fetch_add(1, Ordering::AcqRel);
+        slot.used.store(false, Ordering::Release);
+    }
+}
+
+/// Wake a task of the thread-mode executor
+pub fn wake_task(task_id: usize) {
+    THREAD_EXECUTOR.wake(task_id);
+}
+
+/// Run the tasks on the thread-mode executor, forever
+pub fn run_tasks(tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) -> ! {
+    THREAD_EXECUTOR.run(tasks)
+}
+
+/// Like `run_tasks`, but returns once every task, spawned ones included, has completed
+pub fn run_tasks_to_completion(tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) {
+    THREAD_EXECUTOR.run_to_completion(tasks);
+}
+
+/// Reset the built-in executors, see `Executor::reset`
+pub fn reset() {
+    for (_, executor) in builtin_executors() {
+        executor.reset();
+    }
+    stats::reset_cpu_usage();
+}
+
+/// Runtime statistics of a task on one of the built-in executors, see `Executor::task_stats`
+pub fn task_stats(priority: Priority, task_id: usize) -> Option<TaskStats> {
+    builtin_executor(priority)?.task_stats(task_id)
+}
+
+/// Give the other ready tasks a turn before continuing
+///
+/// The task wakes itself right away, which puts it at the back of the ready queue.
+pub async fn yield_now() {
+    let mut yielded = false;
+
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// Limit how many ready futures the calling task can await per poll, None for no limit
+///
+/// A task whose futures are always ready, e.g. a receiver that never runs
+/// dry, would otherwise never return to the executor and starve all other
+/// tasks. Once the budget is used up, futures checking `poll_budget` return
+/// Pending and the task goes to the back of the ready queue, as with
+/// `yield_now`. Tasks start without a limit.
+pub async fn set_poll_budget(budget: Option<u16>) {
+    // Zero would leave the task stuck, unable to complete any future
+    let budget = budget.map_or(UNLIMITED_BUDGET, |budget| budget.max(1));
+
+    poll_fn(|cx| {
+        if let Some((executor, task_id)) = decode_waker(cx.waker()) {
+            executor.budgets[task_id].store(budget, Ordering::Relaxed);
+            executor.budget_left.store(budget, Ordering::Relaxed);
+        }
+        Poll::Ready(())
+    })
+    .await
+}
+
+/// Take one unit of the polling task's budget, for leaf futures about to return Ready
+///
+/// Returns Pending once the budget is used up, after waking the task, so the
+/// caller has to return Pending too. Always Ready for tasks without a budget
+/// and for futures not polled by one of our executors.
+pub fn poll_budget(cx: &mut Context<'_>) -> Poll<()> {
+    let Some((executor, _)) = decode_waker(cx.waker()) else {
+        return Poll::Ready(());
+    };
+
+    match executor.budget_left.load(Ordering::Relaxed) {
+        UNLIMITED_BUDGET => Poll::Ready(()),
+        0 => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        left => {
+            executor.budget_left.store(left - 1, Ordering::Relaxed);
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Executor and ID of the task being polled, the innermost one if an interrupt executor preempted another
+pub fn current_task() -> Option<(Priority, usize)> {
+    builtin_executors().rev().find_map(|(priority, executor)| {
+        let polling = executor.polling.load(Ordering::Relaxed);
+        (polling != NO_TASK).then(|| (priority, polling - 1))
+    })
+}
+
+/// Poll ready tasks of an interrupt executor, called from its interrupt
+#[cfg(feature = "interrupt-executors")]
+fn run_interrupt_executor(executor: &'static Executor) {
+    while executor.poll_next_task(&mut []) {}
+}
+
+/// Without interrupts, run higher-priority tasks whenever thread mode is between polls
+#[cfg(feature = "std")]
+fn run_interrupt_executors() {
+    // Start over from the top after every poll, the task may have woken a higher one
+    while builtin_executors()
+        .rev()
+        .filter(|(priority, _)| *priority != Priority::Thread)
+        .any(|(_, executor)| executor.poll_next_task(&mut []))
+    {}
+}
+
+// Interrupt executor handlers, the vectors are otherwise unused on this board
+#[cfg(feature = "interrupt-executors")]
+#[interrupt]
+fn TSC() {
+    run_interrupt_executor(&MEDIUM_EXECUTOR);
+}
+
+#[cfg(feature = "interrupt-executors")]
+#[interrupt]
+fn CEC_CAN() {
+    run_interrupt_executor(&HIGH_EXECUTOR);
+}
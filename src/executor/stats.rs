@@ -0,0 +1,160 @@
+use core::cell::{Cell, RefCell};
+
+use critical_section::Mutex;
+use fugit::TimerDuration;
+use rtt_target::rprintln;
+
+use super::{builtin_executors, TOTAL_TASKS};
+use crate::ticker::{TickInstant, Ticker, TICK_HZ};
+
+type TickDuration64 = TimerDuration<u64, TICK_HZ>;
+
+const NO_STATS: TaskStats = TaskStats {
+    polls: 0,
+    wakes: 0,
+    busy_ticks: 0,
+    max_poll_ticks: 0,
+    last_run: None,
+};
+
+// Ticks the thread-mode executor spent sleeping, and the time counting started
+static IDLE_TICKS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+static COUNTING_SINCE: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Runtime statistics of one task
+///
+/// Times are measured with the ticker, Cortex-M0 has no DWT cycle counter.
+/// A poll shorter than a tick only adds a tick if it crosses a tick boundary,
+/// which averages out over many polls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaskStats {
+    /// Number of times the task was polled
+    pub polls: u32,
+    /// Number of wakes, including those while the task was already queued
+    pub wakes: u32,
+    /// Total time spent polling the task, in ticks
+    pub busy_ticks: u64,
+    /// Longest single poll, in ticks
+    pub max_poll_ticks: u64,
+    /// Start of the last poll
+    pub last_run: Option<TickInstant>,
+}
+
+/// Statistics of all task slots of one executor
+pub(super) struct StatsTable {
+    tasks: Mutex<RefCell<[TaskStats; TOTAL_TASKS]>>,
+}
+
+impl StatsTable {
+    pub(super) const fn new() -> Self {
+        Self {
+            tasks: Mutex::new(RefCell::new([NO_STATS; TOTAL_TASKS])),
+        }
+    }
+
+    pub(super) fn get(&self, task_id: usize) -> TaskStats {
+        critical_section::with(|cs| self.tasks.borrow(cs).borrow()[task_id])
+    }
+
+    pub(super) fn record_wake(&self, task_id: usize) {
+        critical_section::with(|cs| {
+            let stats = &mut self.tasks.borrow(cs).borrow_mut()[task_id];
+            stats.wakes = stats.wakes.wrapping_add(1);
+        });
+    }
+
+    pub(super) fn record_poll(&self, task_id: usize, start: TickInstant, end: TickInstant) {
+        let ticks = end.ticks() - start.ticks();
+
+        critical_section::with(|cs| {
+            let stats = &mut self.tasks.borrow(cs).borrow_mut()[task_id];
+            stats.polls = stats.polls.wrapping_add(1);
+            stats.busy_ticks += ticks;
+            stats.max_poll_ticks = stats.max_poll_ticks.max(ticks);
+            stats.last_run = Some(start);
+        });
+    }
+
+    pub(super) fn reset(&self) {
+        critical_section::with(|cs| self.tasks.borrow(cs).replace([NO_STATS; TOTAL_TASKS]));
+    }
+}
+
+/// Time the thread-mode executor spent sleeping, out of the time counted
+///
+/// Interrupt handlers that run while the CPU sleeps, interrupt executors
+/// included, count as idle time. Their tasks show up in the task statistics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuUsage {
+    pub idle_ticks: u64,
+    pub total_ticks: u64,
+}
+
+impl CpuUsage {
+    /// Share of the time spent sleeping, in percent
+    pub fn idle_percent(&self) -> u32 {
+        match self.total_ticks {
+            0 => 100,
+            total => (self.idle_ticks * 100 / total) as u32,
+        }
+    }
+}
+
+/// Idle time since start up, or since the last `executor::reset`
+pub fn cpu_usage() -> CpuUsage {
+    critical_section::with(|cs| CpuUsage {
+        idle_ticks: IDLE_TICKS.borrow(cs).get(),
+        total_ticks: Ticker::now().ticks().saturating_sub(COUNTING_SINCE.borrow(cs).get()),
+    })
+}
+
+/// Count time spent waiting for the next event as idle
+pub(crate) fn record_idle(start: TickInstant, end: TickInstant) {
+    critical_section::with(|cs| {
+        let idle = IDLE_TICKS.borrow(cs);
+        idle.set(idle.get() + (end.ticks() - start.ticks()));
+    });
+}
+
+pub(super) fn reset_cpu_usage() {
+    critical_section::with(|cs| {
+        IDLE_TICKS.borrow(cs).set(0);
+        COUNTING_SINCE.borrow(cs).set(Ticker::now().ticks());
+    });
+}
+
+/// Print a table of all tasks that have run so far to RTT
+pub fn print_stats() {
+    let usage = cpu_usage();
+
+    rprintln!(
+        "Task stats at {} ms, idle {}%",
+        Ticker::now().duration_since_epoch().to_millis(),
+        usage.idle_percent()
+    );
+    rprintln!(
+        "{:8} {:>5} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "executor", "task", "polls", "wakes", "busy ms", "max ms", "last ms"
+    );
+
+    for (priority, executor) in builtin_executors() {
+
+        for task_id in 0..TOTAL_TASKS {
+            let stats = executor.stats.get(task_id);
+            let Some(last_run) = stats.last_run else {
+                continue;
+            };
+
+            rprintln!(
+                "{:8} {:5} {:8} {:8} {:8} {:8} {:8}",
+                priority.name(),
+                task_id,
+                stats.polls,
+                stats.wakes,
+                TickDuration64::from_ticks(stats.busy_ticks).to_millis(),
+                TickDuration64::from_ticks(stats.max_poll_ticks).to_millis(),
+                last_run.duration_since_epoch().to_millis()
+            );
+        }
+    }
+}
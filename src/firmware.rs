@@ -0,0 +1,165 @@
+use zero_to_async::{
+    button::ButtonEvent,
+    button_interrupt::InputChannel,
+    channel::{Channel, Receiver, Sender},
+    crash,
+    executor,
+    power,
+    task,
+    tasks::{button_task, led_task, stats_task},
+    ticker::{TickDuration, Ticker},
+    watchdog,
+};
+#[cfg(not(any(feature = "time-driver-systick", feature = "time-driver-mock")))]
+use zero_to_async::time_driver::Tim2Driver;
+#[cfg(feature = "time-driver-systick")]
+use zero_to_async::time_driver::SysTickDriver;
+#[cfg(feature = "time-driver-mock")]
+use zero_to_async::time_driver::MockDriver;
+
+use core::panic::PanicInfo;
+
+use cortex_m_rt::{entry, exception, ExceptionFrame};
+use rtt_target::{rprintln, rtt_init_print};
+use stm32f0xx_hal::{
+    gpio::{Output, Pin, PushPull},
+    pac,
+    prelude::*,
+};
+
+// Button presses the LED task has not handled yet
+const BUTTON_EVENTS: usize = 4;
+
+// Button events, static so the tasks and interrupt handlers can reach it
+static BUTTON_CHANNEL: Channel<ButtonEvent, BUTTON_EVENTS> = Channel::new();
+
+#[task]
+async fn led(led: Pin<Output<PushPull>>, receiver: Receiver<'static, ButtonEvent, BUTTON_EVENTS>) {
+    led_task(led, receiver).await;
+}
+
+#[task]
+async fn button(input: InputChannel, sender: Sender<'static, ButtonEvent, BUTTON_EVENTS>) {
+    button_task(input, sender).await;
+}
+
+#[task]
+async fn stats() {
+    stats_task(TickDuration::secs(10)).await;
+}
+
+#[entry]
+fn main() -> ! {
+    // Initialize RTT
+    rtt_init_print!();
+    rprintln!("Starting program...");
+
+    if let Some(report) = crash::take_report() {
+        rprintln!("Crashed before the last reset: {}", report);
+    }
+    if let Some((priority, task_id)) = watchdog::take_stuck_task() {
+        rprintln!("Reset by the watchdog, task {} on {:?} executor missed its check-in", task_id, priority);
+    }
+
+    // Get access to the device peripherals
+    let mut dp = pac::Peripherals::take().unwrap();
+
+    rprintln!("Peripherals taken");
+
+    // CRITICAL: Enable SYSCFG clock BEFORE calling RCC configure()
+    // This is required for EXTI external interrupt pin mapping to work
+    rprintln!("Enabling SYSCFG clock...");
+    dp.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+    // Add delay to ensure clock propagation
+    cortex_m::asm::delay(1000);
+
+    // Verify SYSCFG clock is enabled and test register access
+    let apb2enr = dp.RCC.apb2enr.read().bits();
+    rprintln!("RCC APB2ENR: 0x{:08x} (SYSCFG clock enabled: {})", 
+             apb2enr, if (apb2enr & 1) != 0 { "YES" } else { "NO" });
+
+    // Now configure the main clock system (this consumes dp.RCC)
+    let mut rcc = dp.RCC.configure()
+        .hsi48()                    // Use HSI48 (48 MHz internal oscillator)
+        .enable_crs(dp.CRS)         // Enable Clock Recovery System
+        .sysclk(48.mhz())           // Set system clock to 48 MHz
+        .pclk(48.mhz())             // Set peripheral clock to 48 MHz
+        .freeze(&mut dp.FLASH);
+
+    rprintln!("Clocks configured");
+
+    // Add stabilization delays
+    cortex_m::asm::delay(10000);
+
+    // Setup GPIO with proper timing
+    let gpioc = dp.GPIOC.split(&mut rcc);
+    let gpioa = dp.GPIOA.split(&mut rcc);
+
+    // Configure button pin and let it stabilize
+    let button_pin = cortex_m::interrupt::free(|cs| {
+        gpioc.pc13.into_pull_up_input(cs).downgrade()
+    });
+    rprintln!("Button pin configured (PC13: Pull-up Input)");
+
+    // Long delay for pull-up to stabilize
+    cortex_m::asm::delay(50000);
+
+    // Configure LED pin
+    let user_led = cortex_m::interrupt::free(|cs| {
+        gpioa.pa5.into_push_pull_output(cs).downgrade()
+    });
+    rprintln!("LED pin configured (PA5: Push-Pull Output");
+
+    // Setup on-demand tick timer
+    #[cfg(not(any(feature = "time-driver-systick", feature = "time-driver-mock")))]
+    Tim2Driver::init(dp.TIM2, &mut rcc);
+    #[cfg(feature = "time-driver-systick")]
+    SysTickDriver::init(cortex_m::Peripherals::take().unwrap().SYST, &rcc);
+    #[cfg(feature = "time-driver-mock")]
+    MockDriver::init();
+    Ticker::init();
+    rprintln!("On-demand ticker initialized");
+
+    // Let the executor use Stop mode while waiting for far away deadlines
+    match power::init(dp.PWR, dp.RTC, &mut dp.EXTI) {
+        Ok(()) => rprintln!("Stop mode enabled"),
+        Err(_) => rprintln!("No LSE crystal, Stop mode disabled"),
+    }
+
+    // Reset if a watched task stops checking in
+    watchdog::start(dp.IWDG, TickDuration::secs(2));
+    rprintln!("Watchdog started");
+
+    // Add delay after timer setup
+    cortex_m::asm::delay(5000);
+
+    let spawner = executor::spawner();
+
+    // Spawn button task (SYSCFG clock was enabled before RCC configure)
+    rprintln!("Spawning button task...");
+    let exti_line_user_button = 13;
+    let input = InputChannel::new(button_pin, exti_line_user_button, &mut dp.SYSCFG, &mut dp.EXTI);
+    spawner.spawn_task(button(input, BUTTON_CHANNEL.get_sender())).unwrap();
+    rprintln!("Button task spawned");
+
+    // Spawn LED task
+    spawner.spawn_task(led(user_led, BUTTON_CHANNEL.get_receiver())).unwrap();
+    rprintln!("LED task spawned");
+
+    // Spawn task printing CPU usage per task
+    spawner.spawn_task(stats()).unwrap();
+
+    rprintln!("Starting executor...");
+    executor::run_tasks(&mut []);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crash::record_panic(info)
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    crash::record_hard_fault(frame)
+}
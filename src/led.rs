@@ -1,21 +1,18 @@
-use fugit::MillisDurationU32;
-use stm32f0xx_hal::{
-    gpio::{Pin, Output, PushPull},
-    prelude::_embedded_hal_gpio_ToggleableOutputPin,
-};
+use core::convert::Infallible;
+use stm32f0xx_hal::prelude::_embedded_hal_gpio_ToggleableOutputPin;
 
 use crate::ticker::TickDuration;
 
-pub struct LedThing {
-    led: Pin<Output<PushPull>>,
+pub struct LedThing<P> {
+    led: P,
     blink_period: TickDuration,
 }
 
-impl LedThing {
-    pub fn new(led: Pin<Output<PushPull>>) -> Self {
+impl<P: _embedded_hal_gpio_ToggleableOutputPin<Error = Infallible>> LedThing<P> {
+    pub fn new(led: P) -> Self {
         Self {
             led,
-            blink_period: MillisDurationU32::from_ticks(500),
+            blink_period: TickDuration::millis(500),
         }
     }
 
@@ -23,9 +20,9 @@ impl LedThing {
         let current_period = self.blink_period.to_millis();
 
         if current_period < 100 {
-            self.blink_period = MillisDurationU32::from_ticks(500);
+            self.blink_period = TickDuration::millis(500);
         } else {
-            self.blink_period -= MillisDurationU32::from_ticks(current_period >> 1);
+            self.blink_period -= TickDuration::millis(current_period >> 1);
         }
     }
 
@@ -0,0 +1,20 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod ticker;
+pub mod time_driver;
+pub mod channel;
+pub mod mpmc;
+pub mod button;
+pub mod button_interrupt;
+pub mod led;
+pub mod executor;
+pub mod tasks;
+pub mod delay;
+pub mod watchdog;
+pub mod power;
+#[cfg(not(feature = "std"))]
+pub mod crash;
+#[cfg(feature = "std")]
+pub mod sim;
+
+pub use zero_to_async_macros::task;
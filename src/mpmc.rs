@@ -0,0 +1,260 @@
+//! Multi-producer multi-consumer channel
+//!
+//! Like `Channel`, but any number of receivers can take items from the same
+//! queue, e.g. a pool of worker tasks sharing jobs. Waiting senders and
+//! receivers line up in fixed-capacity waker lists and are woken one at a
+//! time in the order they started waiting, so items are handed out in turns.
+//! A line holds every task of an executor, should it still fill up, everyone
+//! in it is woken to check again.
+
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+use critical_section::{CriticalSection, Mutex};
+use heapless::Deque;
+
+use crate::channel::{self, Closed, Place, SendError, SendQueue, Senders, Ticket, TrySendError, WaitQueue};
+use crate::executor;
+
+// Constants, every task of an executor can wait on either side
+const MAX_WAITING_SENDERS: usize = executor::TOTAL_TASKS;
+const MAX_WAITING_RECEIVERS: usize = executor::TOTAL_TASKS;
+
+enum Side {
+    Sender,
+    Receiver,
+}
+
+/// Channel holding up to `N` items, shared by any number of senders and receivers
+///
+/// Protected by critical sections like `Channel`. It closes once a sender
+/// calls `close` or the last `Sender` is dropped, the receivers still get the
+/// items sent before that.
+pub struct MpmcChannel<T, const N: usize> {
+    items: Mutex<RefCell<Deque<T, N>>>,
+    receiver_wakers: Mutex<RefCell<WaitQueue<MAX_WAITING_RECEIVERS>>>,
+    sender_wakers: Mutex<RefCell<WaitQueue<MAX_WAITING_SENDERS>>>,
+    senders: Senders,
+}
+
+impl<T, const N: usize> Default for MpmcChannel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> MpmcChannel<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            items: Mutex::new(RefCell::new(Deque::new())),
+            receiver_wakers: Mutex::new(RefCell::new(WaitQueue::new())),
+            sender_wakers: Mutex::new(RefCell::new(WaitQueue::new())),
+            senders: Senders::new(),
+        }
+    }
+
+    pub fn get_sender(&self) -> Sender<'_, T, N> {
+        self.senders.add();
+        Sender { channel: self }
+    }
+
+    pub fn get_receiver(&self) -> Receiver<'_, T, N> {
+        Receiver { channel: self }
+    }
+
+    /// Send `item` if there is space, otherwise hand it back
+    ///
+    /// Never waits, so it is safe to call from an interrupt handler. The
+    /// receiver that has waited longest is woken.
+    pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        self.push(item, None)
+    }
+
+    /// Take the oldest item, if there is none `waiting` lines up to be woken for the next one
+    ///
+    /// A receive that was woken before but missed its turn waits at the front
+    /// again, one that is done leaves the line.
+    fn pop(&self, ticket: &mut Option<Ticket>, waiting: &Waker) -> Poll<Result<T, Closed>> {
+        let (result, full_line) = critical_section::with(|cs| {
+            let mut receiver_wakers = self.receiver_wakers.borrow(cs).borrow_mut();
+            let mut items = self.items.borrow(cs).borrow_mut();
+            let Some(item) = items.pop_front() else {
+                if self.senders.is_closed(cs) {
+                    receiver_wakers.done(ticket);
+                    return (Poll::Ready(Err(Closed)), None);
+                }
+                let keep_turn = ticket.is_some();
+                return (Poll::Pending, receiver_wakers.register(ticket, waiting, keep_turn));
+            };
+
+            // Still in line if it took the item before the receiver that was woken for it
+            receiver_wakers.done(ticket);
+
+            // One sender per free slot, and the next receiver if there are more items
+            let receiver = if items.is_empty() { None } else { receiver_wakers.next() };
+            (Poll::Ready(Ok((item, self.next_sender(cs), receiver))), None)
+        });
+
+        if let Some(full_line) = full_line {
+            full_line.wake_all();
+        }
+        result.map_ok(|(item, sender, receiver)| {
+            sender.into_iter().chain(receiver).for_each(Waker::wake);
+            item
+        })
+    }
+
+    /// Take a dropped send or receive out of line, its turn goes to the next one if it was woken
+    fn leave(&self, side: &Side, ticket: Ticket) {
+        let next = critical_section::with(|cs| {
+            let items = self.items.borrow(cs).borrow();
+            match side {
+                Side::Sender => {
+                    let mut senders = self.sender_wakers.borrow(cs).borrow_mut();
+                    (!senders.remove(ticket) && !items.is_full()).then(|| senders.next()).flatten()
+                }
+                Side::Receiver => {
+                    let mut receivers = self.receiver_wakers.borrow(cs).borrow_mut();
+                    (!receivers.remove(ticket) && !items.is_empty()).then(|| receivers.next()).flatten()
+                }
+            }
+        });
+
+        if let Some(waker) = next {
+            waker.wake();
+        }
+    }
+
+    fn next_sender(&self, cs: CriticalSection) -> Option<Waker> {
+        self.sender_wakers.borrow(cs).borrow_mut().next()
+    }
+
+    fn is_empty(&self) -> bool {
+        critical_section::with(|cs| self.items.borrow(cs).borrow().is_empty())
+    }
+
+    /// Close the channel and wake everyone waiting on it
+    fn close(&self) {
+        critical_section::with(|cs| self.senders.close(cs));
+
+        // Woken outside the critical section, one at a time
+        while let Some(waker) = critical_section::with(|cs| {
+            let receiver = self.receiver_wakers.borrow(cs).borrow_mut().next();
+            receiver.or_else(|| self.next_sender(cs))
+        }) {
+            waker.wake();
+        }
+    }
+}
+
+impl<T, const N: usize> SendQueue<T> for MpmcChannel<T, N> {
+    // A send that was woken before but missed its turn waits at the front again
+    fn push(&self, item: T, waiting: Option<(&mut Option<Ticket>, &Waker)>) -> Result<(), TrySendError<T>> {
+        let (result, full_line) = critical_section::with(|cs| {
+            let mut sender_wakers = self.sender_wakers.borrow(cs).borrow_mut();
+            let (ticket, waker) = match waiting {
+                Some((ticket, waker)) => (ticket, Some(waker)),
+                None => (&mut None, None),
+            };
+            if self.senders.is_closed(cs) {
+                sender_wakers.done(ticket);
+                return (Err(TrySendError::Closed(item)), None);
+            }
+
+            let mut items = self.items.borrow(cs).borrow_mut();
+            if let Err(item) = items.push_back(item) {
+                // Registered in the same critical section, so no space is made in between
+                let keep_turn = ticket.is_some();
+                let full_line = waker.and_then(|waker| sender_wakers.register(ticket, waker, keep_turn));
+                return (Err(TrySendError::Full(item)), full_line);
+            }
+            // Still in line if it took the space before the sender that was woken for it
+            sender_wakers.done(ticket);
+
+            // One receiver per item, and the next sender if there is still space
+            let sender = if items.is_full() { None } else { sender_wakers.next() };
+            (Ok((self.receiver_wakers.borrow(cs).borrow_mut().next(), sender)), None)
+        });
+
+        if let Some(full_line) = full_line {
+            full_line.wake_all();
+        }
+        let (receiver, sender) = result?;
+        receiver.into_iter().chain(sender).for_each(Waker::wake);
+        Ok(())
+    }
+
+    fn leave_senders(&self, ticket: Ticket) {
+        self.leave(&Side::Sender, ticket);
+    }
+}
+
+/// Sending end of an MPMC channel, clone it for more senders
+pub struct Sender<'a, T, const N: usize> {
+    channel: &'a MpmcChannel<T, N>,
+}
+
+impl<T, const N: usize> Sender<'_, T, N> {
+    /// Send `item`, waiting for space if the channel is full
+    pub async fn send(&self, item: T) -> Result<(), SendError<T>> {
+        channel::send(self.channel, item).await
+    }
+
+    /// Send `item` if there is space, otherwise hand it back
+    pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        self.channel.try_send(item)
+    }
+
+    /// Close the channel for all senders, the receivers still get the items sent so far
+    pub fn close(&self) {
+        self.channel.close();
+    }
+}
+
+impl<T, const N: usize> Clone for Sender<'_, T, N> {
+    fn clone(&self) -> Self {
+        self.channel.get_sender()
+    }
+}
+
+impl<T, const N: usize> Drop for Sender<'_, T, N> {
+    fn drop(&mut self) {
+        if self.channel.senders.remove() {
+            self.channel.close();
+        }
+    }
+}
+
+/// Receiving end of an MPMC channel, clone it for more receivers
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a MpmcChannel<T, N>,
+}
+
+impl<T, const N: usize> Clone for Receiver<'_, T, N> {
+    fn clone(&self) -> Self {
+        self.channel.get_receiver()
+    }
+}
+
+impl<T, const N: usize> Receiver<'_, T, N> {
+    /// Receive the oldest item, waiting in line behind other receivers until there is one
+    ///
+    /// Items already queued are returned on the first poll. Dropping the
+    /// future before it is ready gives up its place in line. Returns
+    /// `Err(Closed)` once the channel is closed and all items are received.
+    pub async fn receive(&self) -> Result<T, Closed> {
+        let mut place = Place::new(|ticket| self.channel.leave(&Side::Receiver, ticket));
+
+        poll_fn(|cx| {
+            // Out of budget, leave the items for the next poll
+            if !self.channel.is_empty() && executor::poll_budget(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            self.channel.pop(&mut place.ticket, cx.waker())
+        }).await
+    }
+}
@@ -0,0 +1,305 @@
+//! Power manager choosing how the idle executor waits
+//!
+//! When no task is ready, the executor waits in Sleep mode: only the core
+//! clock stops and any interrupt ends it. If the earliest ticker deadline is
+//! at least 10 ms away, Stop mode saves a lot more. All clocks except the LSE are
+//! off, so the ticker's timer stops too, and only EXTI lines wake the chip,
+//! the button and the RTC alarm among them. After waking, the system clock
+//! is switched back to HSI48 and the ticker is moved forward by the time the
+//! RTC saw pass.
+//!
+//! Stop mode is only used after `init` found the 32.768 kHz LSE crystal, and
+//! never while a task holds a `no_deep_sleep()` lock.
+//!
+//! The IWDG keeps running in Stop mode. This is fine, since the watchdog
+//! supervisor's own deadline is always among the ticker deadlines.
+
+use critical_section::CriticalSection;
+use portable_atomic::{AtomicU32, Ordering};
+
+#[cfg(not(feature = "std"))]
+use core::cell::RefCell;
+#[cfg(not(feature = "std"))]
+use cortex_m::{asm, peripheral::NVIC};
+#[cfg(not(feature = "std"))]
+use critical_section::Mutex;
+#[cfg(not(feature = "std"))]
+use stm32f0xx_hal::pac::{interrupt, rcc, Interrupt, EXTI, PWR, RCC, RTC};
+
+use crate::ticker::{Ticker, TICK_HZ};
+#[cfg(not(feature = "std"))]
+use crate::ticker::TickDuration;
+use crate::time_driver::{Driver, TimeDriver};
+
+// Constants
+const MIN_STOP_TICKS: u64 = 10 * TICK_HZ as u64 / 1000; // Shorter waits than 10 ms are not worth it
+
+#[cfg(not(feature = "std"))]
+const RTC_HZ: u64 = 4096; // Sub-second counter rate, LSE / (PREDIV_A + 1)
+#[cfg(not(feature = "std"))]
+const RTC_PREDIV_A: u8 = 7;
+#[cfg(not(feature = "std"))]
+const RTC_PREDIV_S: u16 = 4095; // Divides the sub-second counter down to 1 Hz
+#[cfg(not(feature = "std"))]
+const RTC_SS_BITS: u8 = 12; // Sub-second bits the alarm compares
+#[cfg(not(feature = "std"))]
+const RTC_MINUTE: u64 = 60 * RTC_HZ; // The alarm only compares seconds, so it repeats every minute
+#[cfg(not(feature = "std"))]
+const MAX_STOP_RTC_TICKS: u64 = 30 * RTC_HZ; // Well within a minute, longer waits stop again
+#[cfg(not(feature = "std"))]
+const WAKE_MARGIN_RTC_TICKS: u64 = 8; // Wake 2 ms early, the rest is waited in Sleep mode
+#[cfg(not(feature = "std"))]
+const LSE_STARTUP_TIMEOUT: TickDuration = TickDuration::millis(2000); // Crystals take up to 2 s
+
+// Static variables
+static DEEP_SLEEP_LOCKS: AtomicU32 = AtomicU32::new(0);
+
+// Stop mode is only available once `init` has set up the RTC
+#[cfg(not(feature = "std"))]
+static STOP: Mutex<RefCell<Option<(PWR, RTC)>>> = Mutex::new(RefCell::new(None));
+
+/// How the CPU waits for the next event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepMode {
+    /// Core clock stopped, peripherals and the ticker keep running
+    Sleep,
+    /// All clocks but the LSE stopped, woken by EXTI lines only
+    Stop,
+}
+
+/// Keeps the chip out of Stop mode until dropped
+///
+/// Hold one while a peripheral needs its clock, e.g. during a transfer.
+#[must_use]
+pub struct NoDeepSleep {
+    _private: (),
+}
+
+impl Drop for NoDeepSleep {
+    fn drop(&mut self) {
+        DEEP_SLEEP_LOCKS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Forbid Stop mode while the returned lock is held
+pub fn no_deep_sleep() -> NoDeepSleep {
+    DEEP_SLEEP_LOCKS.fetch_add(1, Ordering::Relaxed);
+    NoDeepSleep { _private: () }
+}
+
+/// Error returned by `init` if the LSE crystal did not start
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LseNotReady;
+
+/// Set up the RTC as wake-up source, which enables Stop mode
+///
+/// The RTC runs from the 32.768 kHz LSE crystal, the LSI is too inaccurate
+/// to keep the ticker's time. Without the crystal, the executor keeps using
+/// Sleep mode only. Must be called after `Ticker::init`, with HSI48 or HSI
+/// as system clock.
+#[cfg(not(feature = "std"))]
+pub fn init(pwr: PWR, rtc: RTC, exti: &mut EXTI) -> Result<(), LseNotReady> {
+    // SAFETY: The HAL does not touch the PWR clock or the backup domain after freeze()
+    let rcc = unsafe { &*RCC::ptr() };
+
+    let clock = rcc.cfgr.read().sws();
+    assert!(clock.is_hsi48() || clock.is_hsi(), "Stop mode needs HSI48 or HSI as system clock");
+
+    // Unlock the backup domain, which holds the RTC
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+    // The RTC clock can only be changed by resetting the backup domain
+    if !rcc.bdcr.read().rtcsel().is_lse() {
+        rcc.bdcr.modify(|_, w| w.bdrst().set_bit());
+        rcc.bdcr.modify(|_, w| w.bdrst().clear_bit());
+    }
+    rcc.bdcr.modify(|_, w| w.lseon().set_bit());
+    let give_up = Ticker::now() + LSE_STARTUP_TIMEOUT;
+    while rcc.bdcr.read().lserdy().bit_is_clear() {
+        if Ticker::now() >= give_up {
+            rcc.bdcr.modify(|_, w| w.lseon().clear_bit());
+            return Err(LseNotReady);
+        }
+    }
+    rcc.bdcr.modify(|_, w| w.rtcsel().lse().rtcen().set_bit());
+
+    // Write protection stays off, the power manager owns the RTC
+    rtc.wpr.write(|w| unsafe { w.key().bits(0xCA) });
+    rtc.wpr.write(|w| unsafe { w.key().bits(0x53) });
+
+    rtc.isr.modify(|_, w| w.init().set_bit());
+    while rtc.isr.read().initf().bit_is_clear() {}
+    rtc.prer.write(|w| unsafe { w.prediv_s().bits(RTC_PREDIV_S) });
+    rtc.prer.modify(|_, w| unsafe { w.prediv_a().bits(RTC_PREDIV_A) });
+    rtc.isr.modify(|_, w| w.init().clear_bit());
+
+    // Read the counters directly instead of their shadow copies, which are
+    // stale after Stop mode
+    rtc.cr.modify(|_, w| w.bypshad().set_bit());
+
+    // The RTC alarm is EXTI line 17, it must raise an interrupt to end wfi
+    exti.imr.modify(|_, w| w.mr17().set_bit());
+    exti.rtsr.modify(|_, w| w.tr17().set_bit());
+
+    critical_section::with(|cs| {
+        // Store the peripherals to maintain ownership
+        STOP.borrow(cs).replace(Some((pwr, rtc)));
+    });
+
+    // SAFETY: The RTC handler below only clears the alarm flags
+    unsafe {
+        NVIC::unpend(Interrupt::RTC);
+        NVIC::unmask(Interrupt::RTC);
+    }
+
+    Ok(())
+}
+
+/// Mode the executor would wait in if it went idle now
+pub fn sleep_mode() -> SleepMode {
+    critical_section::with(|cs| choose_sleep_mode(cs).0)
+}
+
+/// Sleep mode and the ticks left until the earliest deadline
+fn choose_sleep_mode(cs: CriticalSection) -> (SleepMode, u64) {
+    let now = Driver::now(cs);
+    let ticks_left = Ticker::next_deadline(cs).map_or(u64::MAX, |deadline| deadline.ticks().saturating_sub(now));
+
+    let stop_allowed = stop_available(cs) && DEEP_SLEEP_LOCKS.load(Ordering::Relaxed) == 0;
+    if stop_allowed && ticks_left >= MIN_STOP_TICKS {
+        (SleepMode::Stop, ticks_left)
+    } else {
+        (SleepMode::Sleep, ticks_left)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+fn stop_available(cs: CriticalSection) -> bool {
+    STOP.borrow(cs).borrow().is_some()
+}
+
+// There is no Stop mode to simulate, the host never sleeps
+#[cfg(feature = "std")]
+fn stop_available(_cs: CriticalSection) -> bool {
+    false
+}
+
+/// Wait for the next interrupt in the cheapest mode possible
+///
+/// Interrupts are masked until the clocks and the time are restored, wfi
+/// still returns once one is pending. `has_work` is checked last, so a task
+/// woken just before cannot be left waiting for the next event.
+#[cfg(not(feature = "std"))]
+pub(crate) fn sleep(has_work: impl FnOnce() -> bool) {
+    critical_section::with(|cs| {
+        if has_work() {
+            return;
+        }
+
+        match choose_sleep_mode(cs) {
+            (SleepMode::Sleep, _) => asm::wfi(),
+            (SleepMode::Stop, ticks_left) => stop(cs, ticks_left),
+        }
+    });
+}
+
+/// Enter Stop mode for at most `ticks`, then restore the clocks and the time
+#[cfg(not(feature = "std"))]
+fn stop(cs: CriticalSection, ticks: u64) {
+    let stop = STOP.borrow(cs).borrow();
+    let Some((pwr, rtc)) = stop.as_ref() else {
+        return;
+    };
+
+    // SAFETY: Only the system clock switch is touched, which Stop mode resets anyway
+    let rcc = unsafe { &*RCC::ptr() };
+    let on_hsi48 = rcc.cfgr.read().sws().is_hsi48();
+
+    let rtc_ticks = (ticks.saturating_mul(RTC_HZ) / TICK_HZ as u64).min(MAX_STOP_RTC_TICKS) - WAKE_MARGIN_RTC_TICKS;
+    let rtc_start = rtc_time(rtc);
+    let ticker_start = Driver::now(cs);
+    set_rtc_alarm(rtc, (rtc_start + rtc_ticks) % RTC_MINUTE);
+
+    // Stop mode with the voltage regulator in low-power mode
+    pwr.cr.modify(|_, w| w.pdds().clear_bit().lpds().set_bit().cwuf().set_bit());
+    let mut scb = unsafe { cortex_m::Peripherals::steal().SCB };
+    scb.set_sleepdeep();
+    asm::wfi();
+    scb.clear_sleepdeep();
+
+    // The chip wakes up running from HSI
+    if on_hsi48 {
+        restore_hsi48(rcc);
+    }
+    rtc.cr.modify(|_, w| w.alrae().clear_bit());
+
+    // The timer may have counted a few ticks before and after, only add the rest
+    let stopped = (rtc_time(rtc) + RTC_MINUTE - rtc_start) % RTC_MINUTE;
+    let counted = Driver::now(cs) - ticker_start;
+    Driver::skip(cs, (stopped * TICK_HZ as u64 / RTC_HZ).saturating_sub(counted));
+}
+
+/// Switch the system clock back to HSI48
+///
+/// The CRS keeps its configuration and trim value through Stop mode.
+#[cfg(not(feature = "std"))]
+fn restore_hsi48(rcc: &rcc::RegisterBlock) {
+    rcc.cr2.modify(|_, w| w.hsi48on().set_bit());
+    while rcc.cr2.read().hsi48rdy().bit_is_clear() {}
+    rcc.cfgr.modify(|_, w| w.sw().hsi48());
+    while !rcc.cfgr.read().sws().is_hsi48() {}
+}
+
+/// RTC time within the current minute, in RTC ticks
+#[cfg(not(feature = "std"))]
+fn rtc_time(rtc: &RTC) -> u64 {
+    loop {
+        // Seconds and sub-seconds belong together if the sub-seconds did not change
+        let subseconds = rtc.ssr.read().ss().bits();
+        let time = rtc.tr.read();
+        if rtc.ssr.read().ss().bits() != subseconds {
+            continue;
+        }
+
+        // The sub-second counter counts down
+        let seconds = (time.st().bits() * 10 + time.su().bits()) as u64;
+        return seconds * RTC_HZ + (RTC_PREDIV_S - subseconds) as u64;
+    }
+}
+
+/// Arm RTC alarm A for `at` RTC ticks within the minute
+#[cfg(not(feature = "std"))]
+fn set_rtc_alarm(rtc: &RTC, at: u64) {
+    let seconds = (at / RTC_HZ) as u8;
+    let subseconds = RTC_PREDIV_S - (at % RTC_HZ) as u16;
+
+    rtc.cr.modify(|_, w| w.alrae().clear_bit());
+    while rtc.isr.read().alrawf().bit_is_clear() {}
+
+    // Compare seconds and sub-seconds only, date, hours and minutes are masked
+    rtc.alrmar.write(|w| unsafe {
+        w.msk4().set_bit()
+            .msk3().set_bit()
+            .msk2().set_bit()
+            .msk1().clear_bit()
+            .st().bits(seconds / 10)
+            .su().bits(seconds % 10)
+    });
+    rtc.alrmassr.write(|w| unsafe { w.maskss().bits(RTC_SS_BITS).ss().bits(subseconds) });
+
+    rtc.isr.modify(|_, w| w.alraf().clear_bit());
+    rtc.cr.modify(|_, w| w.alrae().set_bit().alraie().set_bit());
+}
+
+// RTC interrupt handler, only there to end wfi
+#[cfg(not(feature = "std"))]
+#[interrupt]
+fn RTC() {
+    // SAFETY: Only the alarm flags are cleared, the power manager owns the RTC
+    let rtc = unsafe { &*RTC::ptr() };
+    let exti = unsafe { &*EXTI::ptr() };
+
+    rtc.isr.modify(|_, w| w.alraf().clear_bit());
+    exti.pr.write(|w| w.pif17().set_bit());
+}
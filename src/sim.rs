@@ -0,0 +1,177 @@
+//! Simulation backend for running tasks on a host machine
+//!
+//! Time comes from the mock time driver and only moves when every task is
+//! idle: the executor then jumps straight to the next event, which is either
+//! the earliest entry in the ticker's deadlines or a scripted input edge.
+//! A scenario covering seconds of firmware time runs in milliseconds and
+//! gives the same result on every run.
+
+use core::{convert::Infallible, future::Future, pin::Pin};
+use std::{cell::RefCell, rc::Rc, sync::Mutex, vec::Vec};
+
+use embedded_hal::digital::PinState;
+use portable_atomic::{AtomicBool, Ordering};
+use stm32f0xx_hal::prelude::{_embedded_hal_gpio_InputPin, _embedded_hal_gpio_ToggleableOutputPin};
+
+use crate::button_interrupt::wake_exti_line;
+use crate::executor;
+use crate::ticker::{Ticker, TickInstant};
+use crate::time_driver::MockDriver;
+use crate::watchdog;
+
+// Constants
+const NUM_EXTI_LINES: usize = 16;
+
+// Scripted edges as (time, exti_line, level), sorted by time
+static EDGES: Mutex<Vec<(u64, usize, PinState)>> = Mutex::new(Vec::new());
+
+// Simulated input levels, indexed by EXTI line
+static INPUT_LEVELS: [AtomicBool; NUM_EXTI_LINES] = [const { AtomicBool::new(true) }; NUM_EXTI_LINES];
+
+/// Reset virtual time to 0, clear scripted edges, tasks and the watchdog, and start the ticker
+pub fn init() {
+    EDGES.lock().unwrap().clear();
+    for level in &INPUT_LEVELS {
+        level.store(true, Ordering::Relaxed);
+    }
+
+    watchdog::reset();
+    MockDriver::init();
+    executor::reset();
+    Ticker::init();
+}
+
+/// Schedule an input edge: at `at`, the pin on `exti_line` changes to `level`
+pub fn schedule_edge(at: TickInstant, exti_line: usize, level: PinState) {
+    let mut edges = EDGES.lock().unwrap();
+
+    // Keep the script sorted, edges at the same time stay in schedule order
+    let index = edges.partition_point(|(time, _, _)| *time <= at.ticks());
+    edges.insert(index, (at.ticks(), exti_line, level));
+}
+
+/// Run the tasks until virtual time reaches `end`
+pub fn run_until(end: TickInstant, tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) {
+    executor::THREAD_EXECUTOR.start(tasks);
+
+    loop {
+        executor::THREAD_EXECUTOR.run_ready(tasks);
+
+        // Waiting for the next event is the executor's idle time
+        let start = Ticker::now();
+        let more = advance(end.ticks());
+        executor::record_idle(start, Ticker::now());
+
+        if !more {
+            break;
+        }
+    }
+}
+
+/// Jump to the next event, used by the executor instead of `wfi`
+pub(crate) fn idle() {
+    advance(u64::MAX);
+}
+
+/// Advance virtual time to the next event, returns false if there is none up to `end`
+fn advance(end: u64) -> bool {
+    let next_alarm = MockDriver::next_alarm();
+    let next_edge = EDGES.lock().unwrap().first().map(|(time, _, _)| *time);
+
+    let next = match (next_alarm, next_edge) {
+        (Some(alarm), Some(edge)) => alarm.min(edge),
+        (alarm, edge) => match alarm.or(edge) {
+            Some(next) => next,
+            None => {
+                MockDriver::advance_to(end);
+                return false;
+            }
+        },
+    };
+
+    if next > end {
+        MockDriver::advance_to(end);
+        return false;
+    }
+
+    // Fires the ticker alarm if it is due
+    MockDriver::advance_to(next);
+
+    // Apply all edges due by now, as the EXTI interrupt would
+    loop {
+        let edge = {
+            let mut edges = EDGES.lock().unwrap();
+            match edges.first() {
+                Some((time, _, _)) if *time <= next => Some(edges.remove(0)),
+                _ => None,
+            }
+        };
+
+        let Some((_, exti_line, level)) = edge else {
+            break;
+        };
+
+        let is_high = level == PinState::High;
+        if INPUT_LEVELS[exti_line].swap(is_high, Ordering::Relaxed) != is_high {
+            wake_exti_line(exti_line);
+        }
+    }
+
+    true
+}
+
+/// Simulated input pin, its level is set by scripted edges
+pub struct SimInput {
+    exti_line: usize,
+}
+
+impl SimInput {
+    pub fn new(exti_line: usize) -> Self {
+        Self { exti_line }
+    }
+
+    pub fn exti_line(&self) -> usize {
+        self.exti_line
+    }
+}
+
+impl _embedded_hal_gpio_InputPin for SimInput {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(INPUT_LEVELS[self.exti_line].load(Ordering::Relaxed))
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+/// Simulated output pin that records the time of every toggle
+///
+/// Clones share the same record, so a scenario can keep one to inspect
+/// what the task did with the other.
+#[derive(Clone, Default)]
+pub struct SimOutput {
+    toggles: Rc<RefCell<Vec<TickInstant>>>,
+}
+
+impl SimOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Times at which the pin was toggled, in order
+    pub fn toggle_times(&self) -> Vec<TickInstant> {
+        self.toggles.borrow().clone()
+    }
+}
+
+impl _embedded_hal_gpio_ToggleableOutputPin for SimOutput {
+    type Error = Infallible;
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        self.toggles.borrow_mut().push(Ticker::now());
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use core::convert::Infallible;
+use embedded_hal::digital::PinState;
+use futures::{select_biased, FutureExt};
+use stm32f0xx_hal::prelude::{_embedded_hal_gpio_InputPin, _embedded_hal_gpio_ToggleableOutputPin};
+
+use crate::button::ButtonEvent;
+use crate::button_interrupt::InputChannel;
+use crate::channel::{Closed, Receiver, Sender};
+use crate::executor;
+use crate::led::LedThing;
+use crate::ticker::{self, Interval, TickDuration};
+use crate::watchdog;
+
+pub async fn led_task<P: _embedded_hal_gpio_ToggleableOutputPin<Error = Infallible>, const N: usize>(
+    led: P,
+    mut receiver: Receiver<'_, ButtonEvent, N>
+) {
+    let mut blinker = LedThing::new(led);
+    let mut interval = Interval::new(blinker.get_period());
+
+    // The LED toggles at least every 500 ms, so this leaves plenty of margin
+    let watchdog = watchdog::register(TickDuration::secs(1)).await;
+
+    loop {
+        blinker.toggle();
+        watchdog.check_in();
+
+        select_biased! {
+            button_event = receiver.receive().fuse() => {
+                match button_event {
+                    Ok(ButtonEvent::Pressed) => {
+                        blinker.update_blink_period();
+                        // Restart the blink schedule from this press
+                        interval = Interval::new(blinker.get_period());
+                    }
+                    // The button task is gone, shut down with it
+                    Err(Closed) => return,
+                }
+            }
+            _ = interval.tick().fuse() => {}
+        }
+    }
+}
+
+pub async fn button_task<P: _embedded_hal_gpio_InputPin<Error = Infallible>, const N: usize>(
+    mut input: InputChannel<P>,
+    sender: Sender<'_, ButtonEvent, N>
+) {
+    loop {
+        input.wait_for(PinState::Low).await;
+        if sender.send(ButtonEvent::Pressed).await.is_err() {
+            // The channel was closed, nobody listens for presses anymore
+            return;
+        }
+        ticker::delay(TickDuration::millis(100)).await;
+        input.wait_for(PinState::High).await;
+    }
+}
+
+/// Print the executor statistics to RTT every `period`
+pub async fn stats_task(period: TickDuration) {
+    let mut interval = Interval::new(period);
+
+    loop {
+        interval.tick().await;
+        executor::print_stats();
+    }
+}
@@ -1,43 +1,67 @@
 use core::{
     cell::RefCell,
-    future::Future,
-    pin::Pin,
-    task::{Context, Poll},
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    task::{Context, Poll, Waker},
 };
 use fugit::{TimerDuration, TimerInstant};
+use futures::{select_biased, FutureExt, Stream};
 use heapless::{binary_heap::Min, BinaryHeap};
+use portable_atomic::{AtomicU32, Ordering};
 
-use cortex_m::{
-    interrupt::{free, Mutex, CriticalSection},
-    peripheral::NVIC,
-};
+use critical_section::{CriticalSection, Mutex};
 
-use stm32f0xx_hal::{
-    pac::{interrupt, Interrupt, TIM2},
-    rcc::Rcc,
-    time::Hertz,
-    timers::{Timer},
-};
+use crate::executor;
+use crate::time_driver::{Driver, TimeDriver};
 
-use crate::executor::{wake_task, ExtWaker};
+// Tick rate of the selected time driver
+pub const TICK_HZ: u32 = <Driver as TimeDriver>::TICK_HZ;
 
-// Define our time types with millisecond precision
-pub type TickDuration = TimerDuration<u32, 1000>; // 1ms precision (1000 Hz)
-pub type TickInstant = TimerInstant<u32, 1000>;   // 1ms precision (1000 Hz)
+// Define our time types with the driver's precision
+pub type TickDuration = TimerDuration<u32, TICK_HZ>;
+pub type TickInstant = TimerInstant<u64, TICK_HZ>; // 64-bit, never wraps in practice
 
 // Constants
-const MAX_DEADLINES: usize = 8;
-const TIMER_MAX_COUNT: u32 = 0xFFFFFFFF; // TIM2 is 32-bit
-const HEARTBEAT_INTERVAL_MS: u32 = 24 * 60 * 60 * 1000; // 24 hours in ms
-const HEARTBEAT_TASK_ID: usize = 0xFFFF_FFFF; // Special task ID for heartbeat
+// Up to three timers per task, e.g. a timeout around a select of two delays,
+// plus the internal ones: the heartbeat and the watchdog supervisor
+const MAX_DEADLINES: usize = 3 * executor::TOTAL_TASKS + INTERNAL_TIMERS;
+const INTERNAL_TIMERS: usize = 2;
+const HEARTBEAT_INTERVAL_TICKS: u64 = 24 * 60 * 60 * TICK_HZ as u64; // 24 hours in ticks
+const HEARTBEAT_TIMER_ID: u32 = 0; // Reserved timer ID for heartbeat
+
+// Deadline entry. The timer ID lets a dropped TickTimer find and remove its
+// own entry again, and orders entries with the same deadline.
+struct Deadline {
+    at: u64,
+    timer_id: u32,
+    waker: Option<Waker>, // None for the heartbeat
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.timer_id) == (other.at, other.timer_id)
+    }
+}
+
+impl Eq for Deadline {}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        (self.at, self.timer_id).cmp(&(other.at, other.timer_id))
+    }
+}
 
 // Static variables
-static WAKE_DEADLINES: Mutex<RefCell<BinaryHeap<(u32, usize), Min, MAX_DEADLINES>>> =
+static WAKE_DEADLINES: Mutex<RefCell<BinaryHeap<Deadline, Min, MAX_DEADLINES>>> =
     Mutex::new(RefCell::new(BinaryHeap::new()));
 
-static TICKER: Ticker = Ticker {
-    _tim2: Mutex::new(RefCell::new(None)),
-};
+static NEXT_TIMER_ID: AtomicU32 = AtomicU32::new(HEARTBEAT_TIMER_ID + 1);
 
 // TickTimer struct
 enum TimerState {
@@ -48,25 +72,36 @@ enum TimerState {
 pub struct TickTimer {
     end_time: TickInstant,
     state: TimerState,
+    id: u32,
 }
 
 impl TickTimer {
     pub fn new(duration: TickDuration) -> Self {
+        Self::at(Ticker::now() + duration)
+    }
+
+    /// Timer that expires at an absolute instant
+    pub fn at(end_time: TickInstant) -> Self {
         Self {
-            end_time: Ticker::now() + duration,
+            end_time,
             state: TimerState::Init,
+            id: next_timer_id(),
         }
     }
 
     /// Register this timer's deadline and set compare interrupt if needed
-    fn register(&self, task_id: usize) {
-        let global_deadline = self.end_time.ticks();
-
-        free(|cs| {
+    fn register(&self, waker: &Waker) {
+        let deadline = Deadline {
+            at: self.end_time.ticks(),
+            timer_id: self.id,
+            waker: Some(waker.clone()),
+        };
+
+        critical_section::with(|cs| {
             let mut deadlines = WAKE_DEADLINES.borrow(cs).borrow_mut();
 
-            if deadlines.push((global_deadline, task_id)).is_err() {
-                panic!("Deadline dropped for task {}!", task_id);
+            if deadlines.push(deadline).is_err() {
+                panic!("Deadline dropped for timer {}!", self.id);
             }
 
             drop(deadlines);  // Release borrow before calling other functions
@@ -75,6 +110,58 @@ impl TickTimer {
             update_compare_for_earliest_deadline(cs);
         });
     }
+
+    /// Replace the stored waker if the timer is now polled from somewhere else
+    fn update_waker(&self, waker: &Waker) {
+        critical_section::with(|cs| {
+            let mut deadlines = WAKE_DEADLINES.borrow(cs).borrow_mut();
+
+            // Changing the waker does not affect the heap order
+            if let Some(entry) = deadlines.iter_mut().find(|entry| entry.timer_id == self.id)
+                && !entry.waker.as_ref().is_some_and(|w| w.will_wake(waker))
+            {
+                entry.waker = Some(waker.clone());
+            }
+        });
+    }
+
+    /// Remove this timer's deadline (if it has not fired yet)
+    fn deregister(&self) {
+        critical_section::with(|cs| {
+            let mut deadlines = WAKE_DEADLINES.borrow(cs).borrow_mut();
+
+            // BinaryHeap has no remove(), so rebuild it without our entry
+            let entries = core::mem::take(&mut *deadlines).into_vec();
+            for entry in entries.into_iter().filter(|entry| entry.timer_id != self.id) {
+                // Cannot fail: we only put back what was already there
+                deadlines.push(entry).ok();
+            }
+
+            drop(deadlines);  // Release borrow before calling other functions
+
+            // The removed entry may have been the earliest one
+            update_compare_for_earliest_deadline(cs);
+        });
+    }
+}
+
+impl Drop for TickTimer {
+    fn drop(&mut self) {
+        // Only a registered timer can have an entry in WAKE_DEADLINES
+        if let TimerState::Wait = self.state {
+            self.deregister();
+        }
+    }
+}
+
+/// Hand out a unique ID per timer (0 is reserved for the heartbeat)
+fn next_timer_id() -> u32 {
+    let id = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+    if id == HEARTBEAT_TIMER_ID {
+        NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed)
+    } else {
+        id
+    }
 }
 
 impl Future for TickTimer {
@@ -83,7 +170,7 @@ impl Future for TickTimer {
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.state {
             TimerState::Init => {
-                self.register(cx.waker().task_id());
+                self.register(cx.waker());
                 self.state = TimerState::Wait;
                 Poll::Pending
             }
@@ -91,6 +178,7 @@ impl Future for TickTimer {
                 if Ticker::now() >= self.end_time {
                     Poll::Ready(())
                 } else {
+                    self.update_waker(cx.waker());
                     Poll::Pending
                 }
             }
@@ -102,220 +190,239 @@ pub async fn delay(duration: TickDuration) {
     TickTimer::new(duration).await;
 }
 
-// Ticker struct
-pub struct Ticker {
-    _tim2: Mutex<RefCell<Option<Timer<TIM2>>>>,
-}
+/// Error returned when a future did not complete before its timeout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed;
 
-impl Ticker {
-    pub fn init(tim2: TIM2, rcc: &mut Rcc) {
-        // Create HAL timer object to consume the peripheral
-        let timer = Timer::tim2(tim2, Hertz(1000), rcc);
-
-        // Do manual timer configuration
-        unsafe {
-            let tim2_reg = &*TIM2::ptr();
-
-            // Stop timer
-            tim2_reg.cr1.modify(|_, w| w.cen().clear_bit());
-
-            // Calculate prescaler for 1ms resolution
-            // Timer clock = PCLK (or PCLK*2 if PCLK is prescaled from HCLK)
-            let timer_clock = if rcc.clocks.hclk().0 == rcc.clocks.pclk().0 {
-                rcc.clocks.pclk().0  // PCLK not prescaled
-            } else {
-                rcc.clocks.pclk().0 * 2  // PCLK prescaled, so timer gets 2x
-            };
-
-            // We want 1000 Hz (1ms per tick)
-            // PSC = (timer_clock / desired_frequency) - 1
-            let prescaler = (timer_clock / 1000) - 1;
-            tim2_reg.psc.write(|w| w.psc().bits(prescaler as u16));
-
-            // Set ARR to maximum for free-running
-            tim2_reg.arr.write(|w| w.bits(TIMER_MAX_COUNT));
-
-            // Reset counter
-            tim2_reg.cnt.reset();
-
-            // Force an update event to load the prescaler value
-            tim2_reg.egr.write(|w| w.ug().set_bit());
-
-            // Clear the update flag that was just set by the update event
-            tim2_reg.sr.modify(|_, w| w.uif().clear_bit());
-
-            // Configure for basic timer operation
-            tim2_reg.cr1.write(|w| w
-                .cen().clear_bit()    // Counter disabled for now
-                .udis().clear_bit()   // Update events enabled
-                .urs().clear_bit()    // Update request source
-                .opm().clear_bit()    // One-pulse mode disabled (continuous)
-                .dir().clear_bit()    // Count up
-                .cms().bits(0)        // Edge-aligned mode
-                .arpe().clear_bit()   // Auto-reload preload disabled
-            );
-
-            // Disable all interrupts initially
-            tim2_reg.dier.write(|w| w.cc1ie().clear_bit().uie().clear_bit());
-
-            // Clear all interrupt flags
-            tim2_reg.sr.write(|w| w.cc1if().clear_bit().uif().clear_bit());
-
-            // Start timer
-            tim2_reg.cr1.modify(|_, w| w.cen().set_bit());
-        }
+/// Run `future`, giving up after `duration`
+pub async fn with_timeout<F: Future>(duration: TickDuration, future: F) -> Result<F::Output, Elapsed> {
+    with_deadline(Ticker::now() + duration, future).await
+}
 
-        free(|cs| {
-            // Store the timer object to maintain ownership
-            TICKER._tim2.borrow(cs).replace(Some(timer));
-            // Queue starts empty, so update_compare_for_earliest_deadline will add heartbeat
-            update_compare_for_earliest_deadline(cs);
-        });
+/// Run `future`, giving up once `deadline` is reached
+///
+/// Whichever side loses is dropped, so a timer that did not fire releases its
+/// deadline and a future that timed out is cancelled.
+pub async fn with_deadline<F: Future>(deadline: TickInstant, future: F) -> Result<F::Output, Elapsed> {
+    let mut future = pin!(future.fuse());
+    let mut timer = TickTimer::at(deadline).fuse();
+
+    select_biased! {
+        output = future => Ok(output),
+        _ = timer => Err(Elapsed),
+    }
+}
 
-        enable_tim2_interrupt();
+/// Timeout methods for any future, e.g. `receiver.receive().timeout(period).await`
+pub trait TimeoutExt: Future + Sized {
+    fn timeout(self, duration: TickDuration) -> impl Future<Output = Result<Self::Output, Elapsed>> {
+        with_timeout(duration, self)
     }
 
-    pub fn now() -> TickInstant {
-        TickInstant::from_ticks(read_timer_counter())
+    fn deadline(self, deadline: TickInstant) -> impl Future<Output = Result<Self::Output, Elapsed>> {
+        with_deadline(deadline, self)
     }
 }
 
-/// Read current timer counter value
-fn read_timer_counter() -> u32 {
-    unsafe {
-        let tim2_reg = &*TIM2::ptr();
-        tim2_reg.cnt.read().bits()
-    }
+impl<F: Future> TimeoutExt for F {}
+
+/// What an `Interval` does when ticks were missed because it was polled late
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissedTickBehavior {
+    /// Fire the missed ticks back to back until the schedule is caught up
+    Burst,
+    /// Restart the schedule one period after the late tick
+    Delay,
+    /// Drop the missed ticks and continue on the original schedule
+    Skip,
+}
+
+/// Periodic timer scheduled against absolute deadlines
+///
+/// Unlike calling `delay(period)` in a loop, the time it takes to get polled
+/// and to run the loop body does not add up: tick N is always due at
+/// `start + N * period`, unless ticks are missed (see `MissedTickBehavior`).
+pub struct Interval {
+    next: TickInstant,
+    period: TickDuration,
+    missed_tick_behavior: MissedTickBehavior,
+    timer: Option<TickTimer>,
 }
 
-/// Set compare register for a specific global deadline
-fn set_compare_for_deadline(cs: &CriticalSection, global_deadline: u32) {
-    let current_global_time = read_timer_counter();
+impl Interval {
+    /// Interval whose first tick is one period from now
+    pub fn new(period: TickDuration) -> Self {
+        Self::new_at(Ticker::now() + period, period)
+    }
+
+    /// Interval whose first tick is at `start`
+    pub fn new_at(start: TickInstant, period: TickDuration) -> Self {
+        assert!(period.ticks() > 0, "Interval period must be non-zero");
 
-    if global_deadline <= current_global_time {
-        // Deadline already passed - wake expired tasks immediately
-        wake_expired_deadlines_now(cs, current_global_time);
-        return;
+        Self {
+            next: start,
+            period,
+            missed_tick_behavior: MissedTickBehavior::Burst,
+            timer: None,
+        }
+    }
+
+    pub fn set_missed_tick_behavior(&mut self, behavior: MissedTickBehavior) {
+        self.missed_tick_behavior = behavior;
+    }
+
+    pub fn period(&self) -> TickDuration {
+        self.period
     }
 
-    let time_until_deadline = global_deadline - current_global_time;
-    let remaining_timer_ticks = TIMER_MAX_COUNT - current_global_time;
+    /// Wait for the next tick, returns the instant it was scheduled for
+    pub async fn tick(&mut self) -> TickInstant {
+        poll_fn(|cx| self.poll_tick(cx)).await
+    }
 
-    // Check if deadline fits within current timer cycle
-    if time_until_deadline <= remaining_timer_ticks {
-        let target_timer_count = current_global_time + time_until_deadline;
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<TickInstant> {
+        let next = self.next;
+        let timer = self.timer.get_or_insert_with(|| TickTimer::at(next));
 
-        unsafe {
-            let tim2_reg = &*TIM2::ptr();
-            tim2_reg.ccr1.write(|w| w.bits(target_timer_count));
-            tim2_reg.dier.modify(|_, w| w.cc1ie().set_bit()); // Compare 1 interrupt enable
+        if Pin::new(timer).poll(cx).is_pending() {
+            return Poll::Pending;
         }
-    } else {
-        // Deadline beyond current cycle - disable compare, will be handled after wraparound
-        disable_compare_interrupt(cs);
+        self.timer = None;
+
+        let now = Ticker::now();
+        let period = self.period;
+
+        self.next = if now < next + period {
+            // On time
+            next + period
+        } else {
+            match self.missed_tick_behavior {
+                MissedTickBehavior::Burst => next + period,
+                MissedTickBehavior::Delay => now + period,
+                MissedTickBehavior::Skip => {
+                    // First tick of the original schedule after now
+                    let period_ticks = period.ticks() as u64;
+                    let missed = (now - next).ticks() / period_ticks;
+                    next + TimerDuration::<u64, TICK_HZ>::from_ticks((missed + 1) * period_ticks)
+                }
+            }
+        };
+
+        Poll::Ready(next)
     }
 }
 
-/// Disable compare interrupt
-fn disable_compare_interrupt(_cs: &CriticalSection) {
-    unsafe {
-        let tim2_reg = &*TIM2::ptr();
-        tim2_reg.dier.modify(|_, w| w.cc1ie().clear_bit());
+impl Stream for Interval {
+    type Item = TickInstant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_tick(cx).map(Some)
     }
 }
 
-/// Wake all expired deadlines immediately
-fn wake_expired_deadlines_now(cs: &CriticalSection, current_time: u32) {
-    let mut deadlines = WAKE_DEADLINES.borrow(cs).borrow_mut();
+// Ticker struct
+pub struct Ticker;
 
-    // Wake all expired deadlines
-    while let Some((deadline, task_id)) = deadlines.peek() {
-        if *deadline <= current_time {
-            if *task_id != HEARTBEAT_TASK_ID {
-                wake_task(*task_id);  // Only wake real tasks, not heartbeat
-            }
-            deadlines.pop();
-        } else {
-            break;
-        }
+impl Ticker {
+    /// Start deadline handling, after the time driver has been initialized
+    pub fn init() {
+        critical_section::with(|cs| {
+            // Queue starts empty, so update_compare_for_earliest_deadline will add heartbeat
+            update_compare_for_earliest_deadline(cs);
+        });
+    }
+
+    pub fn now() -> TickInstant {
+        TickInstant::from_ticks(critical_section::with(Driver::now))
+    }
+
+    /// Earliest registered deadline, the heartbeat included
+    pub fn next_deadline(cs: CriticalSection) -> Option<TickInstant> {
+        WAKE_DEADLINES.borrow(cs).borrow().peek().map(|deadline| TickInstant::from_ticks(deadline.at))
     }
 
-    drop(deadlines);  // Release borrow
+    /// Number of timers waiting for their deadline, the heartbeat not counted
+    pub fn waiting_timers(cs: CriticalSection) -> usize {
+        WAKE_DEADLINES.borrow(cs).borrow().iter().filter(|deadline| deadline.waker.is_some()).count()
+    }
+}
+
+/// Set the driver alarm for a specific global deadline
+fn set_compare_for_deadline(cs: CriticalSection, global_deadline: u64) {
+    if !Driver::set_alarm(cs, global_deadline) {
+        // Deadline already passed - wake expired tasks immediately
+        wake_expired_deadlines_now(cs, Driver::now(cs));
+    }
+}
+
+/// Wake all expired deadlines immediately
+fn wake_expired_deadlines_now(cs: CriticalSection, current_time: u64) {
+    wake_tasks_with_deadline(cs, current_time);
 
     // After removing expired deadlines, set compare for next earliest (if any)
     update_compare_for_earliest_deadline(cs);
 }
 
 /// Update compare register for the earliest deadline in the heap
-fn update_compare_for_earliest_deadline(cs: &CriticalSection) {
+fn update_compare_for_earliest_deadline(cs: CriticalSection) {
     let deadlines = WAKE_DEADLINES.borrow(cs).borrow();
 
-    if let Some((earliest_deadline, _)) = deadlines.peek() {
-        let earliest = *earliest_deadline;
+    if let Some(earliest_deadline) = deadlines.peek() {
+        let earliest = earliest_deadline.at;
         drop(deadlines);  // Release borrow before calling set_compare
         set_compare_for_deadline(cs, earliest);
     } else {
         // Queue is empty - add heartbeat and set compare for it
         drop(deadlines);  // Release borrow
 
-        let current_time = read_timer_counter();
-        let next_heartbeat = current_time.wrapping_add(HEARTBEAT_INTERVAL_MS);
+        let current_time = Driver::now(cs);
+        let next_heartbeat = current_time + HEARTBEAT_INTERVAL_TICKS;
 
         let mut deadlines = WAKE_DEADLINES.borrow(cs).borrow_mut();
-        if deadlines.push((next_heartbeat, HEARTBEAT_TASK_ID)).is_ok() {
+        let heartbeat = Deadline {
+            at: next_heartbeat,
+            timer_id: HEARTBEAT_TIMER_ID,
+            waker: None,
+        };
+        if deadlines.push(heartbeat).is_ok() {
             drop(deadlines);
             set_compare_for_deadline(cs, next_heartbeat);
         } else {
             // Should never happen since queue was empty
             drop(deadlines);
-            disable_compare_interrupt(cs);
+            Driver::disable_alarm(cs);
         }
     }
 }
 
 /// Wake all tasks with deadlines <= current_time
-fn wake_tasks_with_deadline(cs: &CriticalSection, current_time: u32) {
-    let mut deadlines = WAKE_DEADLINES.borrow(cs).borrow_mut();
-
-    while let Some((deadline, task_id)) = deadlines.peek() {
-        if *deadline <= current_time {
-            if *task_id != HEARTBEAT_TASK_ID {
-                wake_task(*task_id);  // Only wake real tasks, not heartbeat
-            }
-            deadlines.pop();
-        } else {
-            break;
+fn wake_tasks_with_deadline(cs: CriticalSection, current_time: u64) {
+    while let Some(deadline) = pop_expired_deadline(cs, current_time) {
+        if let Some(waker) = deadline.waker {
+            waker.wake();  // Only wake real tasks, not heartbeat
         }
     }
 }
 
-fn enable_tim2_interrupt() {
-    // SAFETY: We enable this interrupt after setting up the TIM2 peripheral
-    // correctly, and we ensure that the TIM2 interrupt handler is properly
-    // defined to handle this interrupt. This operation is safe because 
-    // we maintain exclusive control over the TIM2 peripheral.
-    unsafe {
-        NVIC::unpend(Interrupt::TIM2);
-        NVIC::unmask(Interrupt::TIM2);
+/// Remove the earliest deadline if it is <= current_time
+///
+/// The heap is only borrowed while popping, so wakers run without holding it.
+fn pop_expired_deadline(cs: CriticalSection, current_time: u64) -> Option<Deadline> {
+    let mut deadlines = WAKE_DEADLINES.borrow(cs).borrow_mut();
+
+    if deadlines.peek().is_some_and(|deadline| deadline.at <= current_time) {
+        deadlines.pop()
+    } else {
+        None
     }
 }
 
-// TIM2 interrupt handler
-#[interrupt]
-fn TIM2() {
-    free(|cs| {
-        let tim2_reg = unsafe { &*TIM2::ptr() };
-
-        // Handle compare interrupt (deadline reached)
-        if tim2_reg.sr.read().cc1if().bit_is_set() {
-            tim2_reg.sr.modify(|_, w| w.cc1if().clear_bit());
-
-            // Calculate current time and wake expired tasks
-            let current_time = read_timer_counter();
-            wake_tasks_with_deadline(cs, current_time);
+/// Alarm hook, called by the time driver's interrupt handler when the alarm fires
+pub fn on_alarm(cs: CriticalSection) {
+    // Wake expired tasks
+    let current_time = Driver::now(cs);
+    wake_tasks_with_deadline(cs, current_time);
 
-            // Set compare for next earliest deadline
-            update_compare_for_earliest_deadline(cs);
-        }
-    });
+    // Set alarm for next earliest deadline
+    update_compare_for_earliest_deadline(cs);
 }
@@ -0,0 +1,74 @@
+use critical_section::CriticalSection;
+
+pub mod counter;
+#[cfg(not(any(feature = "time-driver-systick", feature = "time-driver-mock")))]
+mod tim2;
+#[cfg(feature = "time-driver-systick")]
+mod systick;
+#[cfg(feature = "time-driver-mock")]
+mod mock;
+
+#[cfg(not(any(feature = "time-driver-systick", feature = "time-driver-mock")))]
+pub use tim2::Tim2Driver;
+#[cfg(feature = "time-driver-systick")]
+pub use systick::SysTickDriver;
+#[cfg(feature = "time-driver-mock")]
+pub use mock::MockDriver;
+
+#[cfg(all(feature = "time-driver-systick", feature = "time-driver-mock"))]
+compile_error!("Only one time driver feature can be enabled at a time");
+
+/// Tick rate for all time drivers, selected with cargo features
+pub const TICK_HZ: u32 = if cfg!(feature = "tick-hz-1000000") {
+    1_000_000
+} else if cfg!(feature = "tick-hz-100000") {
+    100_000
+} else if cfg!(feature = "tick-hz-10000") {
+    10_000
+} else {
+    1_000
+};
+
+#[cfg(any(
+    all(feature = "tick-hz-10000", feature = "tick-hz-100000"),
+    all(feature = "tick-hz-10000", feature = "tick-hz-1000000"),
+    all(feature = "tick-hz-100000", feature = "tick-hz-1000000"),
+))]
+compile_error!("Only one tick rate feature can be enabled at a time");
+
+/// The time driver used by the ticker, selected with cargo features
+#[cfg(not(any(feature = "time-driver-systick", feature = "time-driver-mock")))]
+pub type Driver = Tim2Driver;
+#[cfg(feature = "time-driver-systick")]
+pub type Driver = SysTickDriver;
+#[cfg(feature = "time-driver-mock")]
+pub type Driver = MockDriver;
+
+/// Hardware time base for the ticker
+///
+/// A driver provides a monotonic 64-bit tick count and a single alarm. When
+/// the alarm time is reached, the driver calls `ticker::on_alarm` from its
+/// interrupt handler. Each driver has its own `init` function, since the
+/// peripherals it needs differ.
+pub trait TimeDriver {
+    /// Tick rate of `now()` and the alarm in Hz
+    const TICK_HZ: u32;
+
+    /// Current time in ticks since the driver was started
+    fn now(cs: CriticalSection) -> u64;
+
+    /// Arm the alarm to fire at `at` ticks, replacing any earlier alarm
+    ///
+    /// Returns `false` without arming if `at` has already passed, so the
+    /// caller can handle the deadline right away instead.
+    fn set_alarm(cs: CriticalSection, at: u64) -> bool;
+
+    /// Disarm the alarm
+    fn disable_alarm(cs: CriticalSection);
+
+    /// Move the time forward by `ticks` that passed while the driver's clock was stopped
+    ///
+    /// Used after Stop mode, where the timer does not count. An alarm that
+    /// was passed fires right away.
+    fn skip(cs: CriticalSection, ticks: u64);
+}
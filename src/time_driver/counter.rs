@@ -0,0 +1,66 @@
+//! 64-bit time from a wrapping 32-bit hardware counter
+//!
+//! The TIM2 driver counts counter overflows in software and arms its compare
+//! channel once an alarm falls into the current counter cycle. The arithmetic
+//! has no register access, so the wraparound cases can be tested on the host.
+
+// Constants
+pub const COUNTER_MAX: u32 = u32::MAX; // TIM2 is 32-bit
+
+/// Combine the overflow count and a counter reading into a 64-bit time
+///
+/// If an overflow is pending and the counter is in its lower half, the counter
+/// has already wrapped and the period is one ahead of `periods`. A high counter
+/// value means the overflow happened after the counter was read.
+pub fn extend_counter(periods: u32, counter: u32, overflow_pending: bool) -> u64 {
+    let periods = if overflow_pending && counter < COUNTER_MAX / 2 {
+        periods.wrapping_add(1)
+    } else {
+        periods
+    };
+
+    ((periods as u64) << 32) | counter as u64
+}
+
+/// What the compare channel has to do for an alarm
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compare {
+    /// The alarm time has passed, handle it right away
+    Passed,
+    /// The alarm is in the current counter cycle, match the counter at this value
+    Arm(u32),
+    /// The alarm is in a later cycle, the overflow interrupt decides again
+    Later,
+}
+
+/// Compare setting for `alarm` at time `now`
+pub fn compare_for(alarm: u64, now: u64) -> Compare {
+    if alarm <= now {
+        Compare::Passed
+    } else if alarm >> 32 == now >> 32 {
+        Compare::Arm(alarm as u32)
+    } else {
+        Compare::Later
+    }
+}
+
+/// Arm the compare channel for `alarm`, the driver's whole arming decision
+///
+/// `read` gives the overflow count, the counter and whether an overflow is
+/// pending, `write` sets the compare value and enables its interrupt. After
+/// writing, the time is read again: a counter that passed the compare value
+/// meanwhile only matches a full cycle later, so the alarm is `Passed`.
+pub fn arm_alarm(alarm: u64, mut read: impl FnMut() -> (u32, u32, bool), write: impl FnOnce(u32)) -> Compare {
+    let mut now = || {
+        let (periods, counter, overflow_pending) = read();
+        extend_counter(periods, counter, overflow_pending)
+    };
+
+    match compare_for(alarm, now()) {
+        Compare::Arm(compare) => {
+            write(compare);
+            if alarm <= now() { Compare::Passed } else { Compare::Arm(compare) }
+        }
+        decision => decision,
+    }
+}
@@ -0,0 +1,103 @@
+use core::cell::Cell;
+
+use critical_section::{CriticalSection, Mutex};
+
+use super::TimeDriver;
+use crate::ticker;
+
+// Constants
+const NO_ALARM: u64 = u64::MAX;
+
+// Static variables
+static NOW: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+static ALARM: Mutex<Cell<u64>> = Mutex::new(Cell::new(NO_ALARM));
+
+/// Host-side time driver with a clock that only moves when told to
+///
+/// Time starts at 0 and is advanced explicitly, so code using the ticker
+/// behaves the same on every run. An alarm that is passed while advancing
+/// fires at its exact time, as if the interrupt came right on schedule.
+pub struct MockDriver;
+
+impl MockDriver {
+    pub fn init() {
+        critical_section::with(|cs| {
+            NOW.borrow(cs).set(0);
+            ALARM.borrow(cs).set(NO_ALARM);
+        });
+    }
+
+    /// Move the clock forward by `ticks`, firing alarms on the way
+    pub fn advance(ticks: u64) {
+        let target = critical_section::with(|cs| NOW.borrow(cs).get()) + ticks;
+        Self::advance_to(target);
+    }
+
+    /// Move the clock forward to `target` ticks, firing alarms on the way
+    pub fn advance_to(target: u64) {
+        loop {
+            let fired = critical_section::with(|cs| {
+                let alarm = ALARM.borrow(cs).get();
+                if alarm > target {
+                    return false;
+                }
+
+                // Stop at the alarm time, so woken code sees the time it asked for
+                NOW.borrow(cs).set(alarm);
+                ALARM.borrow(cs).set(NO_ALARM);
+                ticker::on_alarm(cs);
+                true
+            });
+
+            if !fired {
+                break;
+            }
+        }
+
+        critical_section::with(|cs| {
+            let now = NOW.borrow(cs);
+            now.set(now.get().max(target));
+        });
+    }
+
+    /// Time of the armed alarm, if any
+    pub fn next_alarm() -> Option<u64> {
+        critical_section::with(|cs| {
+            let alarm = ALARM.borrow(cs).get();
+            (alarm != NO_ALARM).then_some(alarm)
+        })
+    }
+}
+
+impl TimeDriver for MockDriver {
+    const TICK_HZ: u32 = super::TICK_HZ;
+
+    fn now(cs: CriticalSection) -> u64 {
+        NOW.borrow(cs).get()
+    }
+
+    fn set_alarm(cs: CriticalSection, at: u64) -> bool {
+        if at <= Self::now(cs) {
+            ALARM.borrow(cs).set(NO_ALARM);
+            return false;
+        }
+
+        ALARM.borrow(cs).set(at);
+        true
+    }
+
+    fn disable_alarm(cs: CriticalSection) {
+        ALARM.borrow(cs).set(NO_ALARM);
+    }
+
+    fn skip(cs: CriticalSection, ticks: u64) {
+        // Like the hardware drivers, the alarm fires late rather than on schedule
+        let now = NOW.borrow(cs).get() + ticks;
+        NOW.borrow(cs).set(now);
+
+        if now >= ALARM.borrow(cs).get() {
+            ALARM.borrow(cs).set(NO_ALARM);
+            ticker::on_alarm(cs);
+        }
+    }
+}
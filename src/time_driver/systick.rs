@@ -0,0 +1,98 @@
+use core::cell::Cell;
+
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use critical_section::{CriticalSection, Mutex};
+use cortex_m_rt::exception;
+use stm32f0xx_hal::rcc::Rcc;
+
+use super::TimeDriver;
+use crate::ticker;
+
+// SysTick interrupts on every tick, at higher rates the CPU would do nothing else
+#[cfg(any(feature = "tick-hz-100000", feature = "tick-hz-1000000"))]
+compile_error!("The SysTick time driver supports tick rates up to 10 kHz");
+
+// Constants
+const SYST_MAX_RELOAD: u32 = 0x00FF_FFFF; // SysTick is 24-bit
+const NO_ALARM: u64 = u64::MAX;
+
+// Static variables
+static SYSTICK_DRIVER: Mutex<Cell<Option<SYST>>> = Mutex::new(Cell::new(None));
+
+// Ticks counted by the SysTick exception
+static TICKS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+static ALARM: Mutex<Cell<u64>> = Mutex::new(Cell::new(NO_ALARM));
+
+/// Time driver using the Cortex-M SysTick timer
+///
+/// SysTick can't be programmed for an arbitrary point in time, so it
+/// interrupts on every tick and compares against the alarm in software.
+/// This leaves all STM32 timers free, e.g. when TIM2 is needed for PWM.
+pub struct SysTickDriver;
+
+impl SysTickDriver {
+    pub fn init(mut syst: SYST, rcc: &Rcc) {
+        // SysTick runs from the core clock (HCLK)
+        let reload = rcc.clocks.hclk().0 / Self::TICK_HZ - 1;
+        assert!(reload <= SYST_MAX_RELOAD, "Tick rate too low for SysTick");
+
+        syst.disable_counter();
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(reload);
+        syst.clear_current();
+        syst.enable_interrupt();
+        syst.enable_counter();
+
+        critical_section::with(|cs| {
+            // Store the SysTick peripheral to maintain ownership
+            SYSTICK_DRIVER.borrow(cs).set(Some(syst));
+        });
+    }
+}
+
+impl TimeDriver for SysTickDriver {
+    const TICK_HZ: u32 = super::TICK_HZ;
+
+    fn now(cs: CriticalSection) -> u64 {
+        TICKS.borrow(cs).get()
+    }
+
+    fn set_alarm(cs: CriticalSection, at: u64) -> bool {
+        if at <= Self::now(cs) {
+            ALARM.borrow(cs).set(NO_ALARM);
+            return false;
+        }
+
+        ALARM.borrow(cs).set(at);
+        true
+    }
+
+    fn disable_alarm(cs: CriticalSection) {
+        ALARM.borrow(cs).set(NO_ALARM);
+    }
+
+    fn skip(cs: CriticalSection, ticks: u64) {
+        let ticks = TICKS.borrow(cs).get() + ticks;
+        TICKS.borrow(cs).set(ticks);
+
+        if ticks >= ALARM.borrow(cs).get() {
+            ALARM.borrow(cs).set(NO_ALARM);
+            ticker::on_alarm(cs);
+        }
+    }
+}
+
+// SysTick exception handler
+#[exception]
+fn SysTick() {
+    critical_section::with(|cs| {
+        let ticks = TICKS.borrow(cs).get() + 1;
+        TICKS.borrow(cs).set(ticks);
+
+        if ticks >= ALARM.borrow(cs).get() {
+            ALARM.borrow(cs).set(NO_ALARM);
+            ticker::on_alarm(cs);
+        }
+    });
+}
@@ -0,0 +1,261 @@
+use core::cell::{Cell, RefCell};
+use portable_atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::NVIC;
+use critical_section::{CriticalSection, Mutex};
+
+use stm32f0xx_hal::{
+    pac::{interrupt, tim2, Interrupt, TIM2},
+    rcc::Rcc,
+    time::Hertz,
+    timers::{Timer},
+};
+
+use super::counter::{arm_alarm, extend_counter, Compare, COUNTER_MAX};
+use super::TimeDriver;
+use crate::ticker;
+
+// Constants
+const NO_ALARM: u64 = u64::MAX;
+
+// Static variables
+static TIM2_DRIVER: Mutex<RefCell<Option<Timer<TIM2>>>> = Mutex::new(RefCell::new(None));
+
+// Number of completed TIM2 counter cycles, i.e. the upper 32 bits of the time
+static TIMER_PERIODS: AtomicU32 = AtomicU32::new(0);
+
+// Alarm time, kept so the overflow interrupt can arm it once it is in the current cycle
+static ALARM: Mutex<Cell<u64>> = Mutex::new(Cell::new(NO_ALARM));
+
+/// Time driver using the 32-bit TIM2 counter with compare channel 1 as alarm
+pub struct Tim2Driver;
+
+impl Tim2Driver {
+    pub fn init(tim2: TIM2, rcc: &mut Rcc) {
+        // Create HAL timer object to consume the peripheral
+        let timer = Timer::tim2(tim2, Hertz(Self::TICK_HZ), rcc);
+
+        // Do manual timer configuration
+        unsafe {
+            let tim2_reg = &*TIM2::ptr();
+
+            // Stop timer
+            tim2_reg.cr1.modify(|_, w| w.cen().clear_bit());
+
+            // Calculate prescaler for the tick rate
+            // Timer clock = PCLK (or PCLK*2 if PCLK is prescaled from HCLK)
+            let timer_clock = if rcc.clocks.hclk().0 == rcc.clocks.pclk().0 {
+                rcc.clocks.pclk().0  // PCLK not prescaled
+            } else {
+                rcc.clocks.pclk().0 * 2  // PCLK prescaled, so timer gets 2x
+            };
+
+            let prescaler = prescaler_for(timer_clock, Self::TICK_HZ);
+            tim2_reg.psc.write(|w| w.psc().bits(prescaler));
+
+            // Set ARR to maximum for free-running
+            tim2_reg.arr.write(|w| w.bits(COUNTER_MAX));
+
+            // Reset counter
+            tim2_reg.cnt.reset();
+
+            // Force an update event to load the prescaler value
+            tim2_reg.egr.write(|w| w.ug().set_bit());
+
+            // Clear the update flag that was just set by the update event
+            tim2_reg.sr.modify(|_, w| w.uif().clear_bit());
+
+            // Configure for basic timer operation
+            tim2_reg.cr1.write(|w| w
+                .cen().clear_bit()    // Counter disabled for now
+                .udis().clear_bit()   // Update events enabled
+                .urs().clear_bit()    // Update request source
+                .opm().clear_bit()    // One-pulse mode disabled (continuous)
+                .dir().clear_bit()    // Count up
+                .cms().bits(0)        // Edge-aligned mode
+                .arpe().clear_bit()   // Auto-reload preload disabled
+            );
+
+            // Compare interrupt starts disabled, update (overflow) interrupt
+            // is always on to extend the counter to 64 bits
+            tim2_reg.dier.write(|w| w.cc1ie().clear_bit().uie().set_bit());
+
+            // Clear all interrupt flags
+            tim2_reg.sr.write(|w| w.cc1if().clear_bit().uif().clear_bit());
+
+            // Start timer
+            tim2_reg.cr1.modify(|_, w| w.cen().set_bit());
+        }
+
+        critical_section::with(|cs| {
+            // Store the timer object to maintain ownership
+            TIM2_DRIVER.borrow(cs).replace(Some(timer));
+        });
+
+        enable_tim2_interrupt();
+    }
+}
+
+impl TimeDriver for Tim2Driver {
+    const TICK_HZ: u32 = super::TICK_HZ;
+
+    /// Read the 64-bit time: overflow count in the upper half, TIM2 counter in the lower
+    fn now(cs: CriticalSection) -> u64 {
+        let (periods, counter, overflow_pending) = read_counter(cs);
+        extend_counter(periods, counter, overflow_pending)
+    }
+
+    fn set_alarm(cs: CriticalSection, at: u64) -> bool {
+        ALARM.borrow(cs).set(at);
+        arm_compare(cs)
+    }
+
+    fn disable_alarm(cs: CriticalSection) {
+        ALARM.borrow(cs).set(NO_ALARM);
+        disable_compare_interrupt(cs);
+    }
+
+    fn skip(cs: CriticalSection, ticks: u64) {
+        let time = Self::now(cs) + ticks;
+        let tim2_reg = unsafe { &*TIM2::ptr() };
+
+        // Stop the counter, so it can't wrap while both halves are updated.
+        // A pending overflow is already part of `time`.
+        tim2_reg.cr1.modify(|_, w| w.cen().clear_bit());
+        tim2_reg.cnt.write(|w| w.cnt().bits(time as u32));
+        clear_status_flags(tim2_reg, |w| w.uif().clear_bit());
+        TIMER_PERIODS.store((time >> 32) as u32, Ordering::Relaxed);
+        tim2_reg.cr1.modify(|_, w| w.cen().set_bit());
+
+        if !arm_compare(cs) {
+            ALARM.borrow(cs).set(NO_ALARM);
+            ticker::on_alarm(cs);
+        }
+    }
+}
+
+/// Prescaler that divides `timer_clock` down to `tick_hz`
+///
+/// PSC = (timer_clock / tick_hz) - 1, computed in 32 bits. The result must fit
+/// the 16-bit PSC register and the division must be exact, otherwise ticks
+/// would silently run at the wrong rate.
+fn prescaler_for(timer_clock: u32, tick_hz: u32) -> u16 {
+    assert!(
+        tick_hz <= timer_clock && timer_clock.is_multiple_of(tick_hz),
+        "Tick rate {} Hz can't be derived from timer clock {} Hz", tick_hz, timer_clock
+    );
+
+    match u16::try_from(timer_clock / tick_hz - 1) {
+        Ok(prescaler) => prescaler,
+        Err(_) => panic!("Tick rate {} Hz too low for 16-bit prescaler at {} Hz", tick_hz, timer_clock),
+    }
+}
+
+/// Set compare register for the stored alarm, returns false if it already passed
+fn arm_compare(cs: CriticalSection) -> bool {
+    let alarm = ALARM.borrow(cs).get();
+    if alarm == NO_ALARM {
+        disable_compare_interrupt(cs);
+        return true;
+    }
+
+    let write = |compare| unsafe {
+        let tim2_reg = &*TIM2::ptr();
+        tim2_reg.ccr1.write(|w| w.bits(compare));
+
+        // CC1IF is also set by matches while the interrupt is disabled,
+        // e.g. against the compare value of an alarm removed early
+        clear_status_flags(tim2_reg, |w| w.cc1if().clear_bit());
+        tim2_reg.dier.modify(|_, w| w.cc1ie().set_bit()); // Compare 1 interrupt enable
+    };
+
+    match arm_alarm(alarm, || read_counter(cs), write) {
+        Compare::Arm(_) => true,
+        Compare::Passed => {
+            disable_compare_interrupt(cs);
+            false
+        }
+        Compare::Later => {
+            // Alarm in a later cycle - disable compare, the overflow interrupt
+            // arms it once the counter wraps into the alarm's cycle
+            disable_compare_interrupt(cs);
+            true
+        }
+    }
+}
+
+/// Overflow count, counter and pending overflow, as `extend_counter` takes them
+fn read_counter(_cs: CriticalSection) -> (u32, u32, bool) {
+    let tim2_reg = unsafe { &*TIM2::ptr() };
+
+    // Inside the critical section the TIM2 handler cannot run, so an overflow
+    // that happened since the last update interrupt is still pending in UIF.
+    // The counter must be read before the flag for extend_counter to be correct.
+    let counter = tim2_reg.cnt.read().bits();
+    let overflow_pending = tim2_reg.sr.read().uif().bit_is_set();
+
+    (TIMER_PERIODS.load(Ordering::Relaxed), counter, overflow_pending)
+}
+
+/// Disable compare interrupt
+fn disable_compare_interrupt(_cs: CriticalSection) {
+    unsafe {
+        let tim2_reg = &*TIM2::ptr();
+        tim2_reg.dier.modify(|_, w| w.cc1ie().clear_bit());
+    }
+}
+
+/// Clear TIM2 status flags without a read-modify-write
+///
+/// The flags are cleared by writing 0 and unaffected by writing 1. A modify()
+/// could write back 0 for a flag that got set after the read and lose it,
+/// which for UIF would put the 64-bit time off by a whole counter cycle.
+fn clear_status_flags(
+    tim2_reg: &tim2::RegisterBlock,
+    clear: impl FnOnce(&mut tim2::sr::W) -> &mut tim2::sr::W,
+) {
+    // SAFETY: All SR bits are flags that ignore a written 1
+    tim2_reg.sr.write(|w| clear(unsafe { w.bits(0xFFFF_FFFF) }));
+}
+
+fn enable_tim2_interrupt() {
+    // SAFETY: We enable this interrupt after setting up the TIM2 peripheral
+    // correctly, and we ensure that the TIM2 interrupt handler is properly
+    // defined to handle this interrupt. This operation is safe because
+    // we maintain exclusive control over the TIM2 peripheral.
+    unsafe {
+        NVIC::unpend(Interrupt::TIM2);
+        NVIC::unmask(Interrupt::TIM2);
+    }
+}
+
+// TIM2 interrupt handler
+#[interrupt]
+fn TIM2() {
+    critical_section::with(|cs| {
+        let tim2_reg = unsafe { &*TIM2::ptr() };
+
+        // Handle update interrupt (counter wrapped around)
+        if tim2_reg.sr.read().uif().bit_is_set() {
+            clear_status_flags(tim2_reg, |w| w.uif().clear_bit());
+            TIMER_PERIODS.fetch_add(1, Ordering::Relaxed);
+
+            // The alarm may now be in the current cycle
+            if !arm_compare(cs) {
+                ALARM.borrow(cs).set(NO_ALARM);
+                ticker::on_alarm(cs);
+            }
+        }
+
+        // Handle compare interrupt (alarm reached). CC1IF is cleared when the
+        // alarm is armed, a stale match can only come along with an overflow
+        // and then finds no deadline expired, so the alarm is just armed again.
+        if tim2_reg.sr.read().cc1if().bit_is_set() {
+            clear_status_flags(tim2_reg, |w| w.cc1if().clear_bit());
+
+            disable_compare_interrupt(cs);
+            ALARM.borrow(cs).set(NO_ALARM);
+            ticker::on_alarm(cs);
+        }
+    });
+}
@@ -0,0 +1,282 @@
+//! Task watchdog backed by the independent watchdog (IWDG)
+//!
+//! Tasks register with a timeout and have to check in at least that often.
+//! A supervisor runs from the ticker interrupt, so it keeps running while a
+//! task is stuck inside `poll`. It feeds the IWDG only while every registered
+//! task is on time. Once a task misses its check-in, its executor and ID are
+//! logged to RTT and saved to no-init RAM, feeding stops and the IWDG resets
+//! the chip.
+
+use core::{
+    cell::RefCell,
+    future::{poll_fn, Future},
+    mem::MaybeUninit,
+    pin::Pin,
+    ptr,
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+use critical_section::Mutex;
+use rtt_target::rprintln;
+#[cfg(not(feature = "std"))]
+use stm32f0xx_hal::pac::IWDG;
+
+use crate::executor::{ExtWaker, Priority};
+use crate::ticker::{TickDuration, TickTimer, Ticker};
+
+// Constants
+const MAX_WATCHED_TASKS: usize = 8;
+const SUPERVISOR_DIVIDER: u32 = 4; // Feed 4 times per timeout, the LSI is inaccurate
+const REPORT_MAGIC: u32 = 0x5744_4F47; // "WDOG"
+
+#[cfg(not(feature = "std"))]
+const LSI_HZ: u32 = 40_000;
+#[cfg(not(feature = "std"))]
+const IWDG_MAX_RELOAD: u32 = 0x0FFF; // Reload register is 12-bit
+#[cfg(not(feature = "std"))]
+const IWDG_MAX_PRESCALER: u8 = 6; // Divider 4 << 6 = 256
+
+// A registered task
+#[derive(Clone, Copy)]
+struct Watched {
+    priority: u32, // Saved as `priority as u32`, so an empty table is all zeroes and goes to .bss
+    task_id: usize,
+    timeout: u64,
+    deadline: u64,
+}
+
+// Static variables
+static WATCHED: Mutex<RefCell<[Option<Watched>; MAX_WATCHED_TASKS]>> =
+    Mutex::new(RefCell::new([None; MAX_WATCHED_TASKS]));
+
+// Supervisor timer and its period, None while the watchdog is not running
+static SUPERVISOR: Mutex<RefCell<Option<(TickTimer, TickDuration)>>> = Mutex::new(RefCell::new(None));
+
+#[cfg(not(feature = "std"))]
+static WATCHDOG: Mutex<RefCell<Option<IWDG>>> = Mutex::new(RefCell::new(None));
+
+// Left behind for the next boot, only valid if `magic` is REPORT_MAGIC
+#[repr(C)]
+struct ResetReport {
+    magic: u32,
+    priority: u32,
+    task_id: u32,
+}
+
+// Not zeroed on start up, so the report survives the watchdog reset
+#[cfg_attr(not(feature = "std"), unsafe(link_section = ".uninit.WATCHDOG_REPORT"))]
+static mut RESET_REPORT: MaybeUninit<ResetReport> = MaybeUninit::uninit();
+
+// The supervisor is polled by hand with a waker that runs it again
+static SUPERVISOR_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, supervise_from_waker, supervise_from_waker, noop);
+
+unsafe fn clone(_: *const ()) -> RawWaker {
+    RawWaker::new(ptr::null(), &SUPERVISOR_VTABLE)
+}
+
+unsafe fn supervise_from_waker(_: *const ()) {
+    supervise();
+}
+
+unsafe fn noop(_: *const ()) {}
+
+/// Start the IWDG and the supervisor feeding it
+///
+/// Must be called after `Ticker::init`. The timeout is at most about 26 s.
+/// Once started, the IWDG keeps running until the next reset.
+#[cfg(not(feature = "std"))]
+pub fn start(iwdg: IWDG, timeout: TickDuration) {
+    let (prescaler, reload) = iwdg_config(timeout.to_millis());
+
+    // Unlock the configuration registers and wait until they accept writes
+    iwdg.kr.write(|w| w.key().start());
+    iwdg.kr.write(|w| w.key().enable());
+    while iwdg.sr.read().pvu().bit_is_set() {}
+    iwdg.pr.write(|w| w.pr().bits(prescaler));
+    while iwdg.sr.read().rvu().bit_is_set() {}
+    iwdg.rlr.write(|w| w.rl().bits(reload));
+    while iwdg.sr.read().bits() != 0 {}
+    iwdg.kr.write(|w| w.key().reset());
+
+    critical_section::with(|cs| {
+        // Store the IWDG peripheral to maintain ownership
+        WATCHDOG.borrow(cs).replace(Some(iwdg));
+    });
+
+    start_supervisor(timeout);
+}
+
+/// Start the supervisor, there is no IWDG to feed in the simulation
+#[cfg(feature = "std")]
+pub fn start(timeout: TickDuration) {
+    start_supervisor(timeout);
+}
+
+/// Stop the supervisor and forget all registered tasks, used by `sim::init`
+#[cfg(feature = "std")]
+pub(crate) fn reset() {
+    critical_section::with(|cs| {
+        SUPERVISOR.borrow(cs).replace(None);
+        WATCHED.borrow(cs).replace([None; MAX_WATCHED_TASKS]);
+    });
+}
+
+/// Prescaler and reload value for the timeout, with the LSI at 40 kHz
+#[cfg(not(feature = "std"))]
+fn iwdg_config(timeout_ms: u32) -> (u8, u16) {
+    let lsi_ticks = timeout_ms as u64 * LSI_HZ as u64 / 1000;
+
+    // Smallest divider that fits, for the finest resolution
+    for prescaler in 0..=IWDG_MAX_PRESCALER {
+        let divider = 4u64 << prescaler;
+        let reload = lsi_ticks.div_ceil(divider).max(1) - 1;
+        if reload <= IWDG_MAX_RELOAD as u64 {
+            return (prescaler, reload as u16);
+        }
+    }
+
+    panic!("Watchdog timeout of {} ms too long", timeout_ms);
+}
+
+fn start_supervisor(timeout: TickDuration) {
+    let period = timeout / SUPERVISOR_DIVIDER;
+    assert!(period.ticks() > 0, "Watchdog timeout too short");
+
+    critical_section::with(|cs| {
+        SUPERVISOR.borrow(cs).replace(Some((TickTimer::new(period), period)));
+    });
+
+    poll_supervisor();
+}
+
+/// Check all registered tasks, feed the IWDG and wait for the next round if none is late
+fn supervise() {
+    let now = Ticker::now().ticks();
+    let late = critical_section::with(|cs| {
+        WATCHED
+            .borrow(cs)
+            .borrow()
+            .iter()
+            .flatten()
+            .find(|watched| now > watched.deadline)
+            .map(|watched| (Priority::from_u32(watched.priority), watched.task_id))
+    });
+
+    match late {
+        None => {
+            feed();
+            critical_section::with(|cs| {
+                if let Some((timer, period)) = SUPERVISOR.borrow(cs).borrow_mut().as_mut() {
+                    *timer = TickTimer::new(*period);
+                }
+            });
+            poll_supervisor();
+        }
+        Some((priority, task_id)) => {
+            rprintln!("Watchdog: task {} on {:?} executor missed its check-in, resetting", task_id, priority);
+            save_report(priority, task_id);
+
+            // Without the supervisor nothing feeds the IWDG anymore
+            critical_section::with(|cs| SUPERVISOR.borrow(cs).replace(None));
+        }
+    }
+}
+
+/// Register the supervisor timer, it wakes `supervise` once it expires
+fn poll_supervisor() {
+    let waker = unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &SUPERVISOR_VTABLE)) };
+    let mut cx = Context::from_waker(&waker);
+
+    critical_section::with(|cs| {
+        // The period is at least one tick, so registering never wakes right away
+        if let Some((timer, _)) = SUPERVISOR.borrow(cs).borrow_mut().as_mut() {
+            let _ = Pin::new(timer).poll(&mut cx);
+        }
+    });
+}
+
+fn feed() {
+    #[cfg(not(feature = "std"))]
+    critical_section::with(|cs| {
+        if let Some(iwdg) = WATCHDOG.borrow(cs).borrow().as_ref() {
+            iwdg.kr.write(|w| w.key().reset());
+        }
+    });
+}
+
+/// Handle of a task under watch, the task is no longer watched once dropped
+pub struct WatchdogHandle {
+    slot: usize,
+}
+
+impl WatchdogHandle {
+    /// Tell the watchdog this task is alive, the next check-in is due within the timeout
+    pub fn check_in(&self) {
+        let now = Ticker::now().ticks();
+
+        critical_section::with(|cs| {
+            if let Some(watched) = WATCHED.borrow(cs).borrow_mut()[self.slot].as_mut() {
+                watched.deadline = now + watched.timeout;
+            }
+        });
+    }
+}
+
+impl Drop for WatchdogHandle {
+    fn drop(&mut self) {
+        critical_section::with(|cs| WATCHED.borrow(cs).borrow_mut()[self.slot] = None);
+    }
+}
+
+/// Put the calling task under watch, it has to check in at least every `timeout`
+///
+/// The first check-in is due one timeout from now. Panics if the task does
+/// not run on one of this crate's executors or too many tasks are watched.
+pub async fn register(timeout: TickDuration) -> WatchdogHandle {
+    let (priority, task_id) = poll_fn(|cx| Poll::Ready(cx.waker().task()))
+        .await
+        .expect("Watchdog tasks must run on an executor");
+    let timeout = timeout.ticks() as u64;
+    let now = Ticker::now().ticks();
+
+    critical_section::with(|cs| {
+        let mut watched = WATCHED.borrow(cs).borrow_mut();
+        let slot = watched
+            .iter()
+            .position(Option::is_none)
+            .expect("Too many tasks under watch");
+
+        watched[slot] = Some(Watched {
+            priority: priority as u32,
+            task_id,
+            timeout,
+            deadline: now + timeout,
+        });
+        WatchdogHandle { slot }
+    })
+}
+
+fn save_report(priority: Priority, task_id: usize) {
+    let report = (&raw mut RESET_REPORT).cast::<ResetReport>();
+
+    unsafe {
+        ptr::write_volatile(&raw mut (*report).priority, priority as u32);
+        ptr::write_volatile(&raw mut (*report).task_id, task_id as u32);
+        ptr::write_volatile(&raw mut (*report).magic, REPORT_MAGIC);
+    }
+}
+
+/// Executor and ID of the task that caused the last watchdog reset, cleared once read
+pub fn take_stuck_task() -> Option<(Priority, usize)> {
+    let report = (&raw mut RESET_REPORT).cast::<ResetReport>();
+
+    unsafe {
+        if ptr::read_volatile(&raw const (*report).magic) != REPORT_MAGIC {
+            return None;
+        }
+
+        ptr::write_volatile(&raw mut (*report).magic, 0);
+        let priority = Priority::from_u32(ptr::read_volatile(&raw const (*report).priority));
+        Some((priority, ptr::read_volatile(&raw const (*report).task_id) as usize))
+    }
+}
@@ -0,0 +1,23 @@
+//! Shared setup of the simulation tests
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use zero_to_async::{
+    sim,
+    ticker::{TickDuration, TickInstant},
+};
+
+// The simulation is global, so the tests of one binary take turns
+static SIM: Mutex<()> = Mutex::new(());
+
+/// Reset the simulation, it belongs to the calling test until the guard is dropped
+pub fn start() -> MutexGuard<'static, ()> {
+    // A failed test leaves the lock poisoned, the next one resets the state anyway
+    let guard = SIM.lock().unwrap_or_else(PoisonError::into_inner);
+    sim::init();
+    guard
+}
+
+pub fn at_millis(millis: u32) -> TickInstant {
+    TickInstant::from_ticks(0) + TickDuration::millis(millis)
+}
@@ -0,0 +1,61 @@
+//! Host simulation of the button and LED tasks
+//!
+//! Presses the button 5 times within 300 ms and checks how the LED blink
+//! period reacts, with the 100 ms debounce in `button_task` swallowing
+//! every other press.
+//!
+//! cargo test --test sim_button_blink --features std --target x86_64-unknown-linux-gnu
+
+use core::pin::pin;
+use embedded_hal::digital::PinState;
+
+use zero_to_async::{
+    button::ButtonEvent,
+    button_interrupt::InputChannel,
+    channel::Channel,
+    sim::{self, SimInput, SimOutput},
+    tasks::{button_task, led_task},
+};
+
+mod common;
+use common::at_millis;
+
+const BUTTON_EXTI_LINE: usize = 13;
+
+#[test]
+fn presses_shorten_blink_period() {
+    let _sim = common::start();
+
+    // 5 presses of 20 ms each, 60 ms apart, starting at t = 1 s
+    for press in 0..5 {
+        let start = 1000 + 60 * press;
+        sim::schedule_edge(at_millis(start), BUTTON_EXTI_LINE, PinState::Low);
+        sim::schedule_edge(at_millis(start + 20), BUTTON_EXTI_LINE, PinState::High);
+    }
+
+    let led = SimOutput::new();
+    let channel: Channel<ButtonEvent, 4> = Channel::new();
+
+    let input = InputChannel::new_sim(SimInput::new(BUTTON_EXTI_LINE));
+    let button_task = pin!(button_task(input, channel.get_sender()));
+    let led_task = pin!(led_task(led.clone(), channel.get_receiver()));
+
+    sim::run_until(at_millis(2000), &mut [led_task, button_task]);
+
+    let toggles: Vec<u64> = led
+        .toggle_times()
+        .iter()
+        .map(|t| t.duration_since_epoch().to_millis())
+        .collect();
+    println!("LED toggled at {:?}", toggles);
+
+    // Blinks at 500 ms until the first press, which lands on the 1000 ms toggle
+    assert_eq!(&toggles[..3], &[0, 500, 1000]);
+
+    // Presses at 1000, 1120 and 1240 ms get through the debounce, each one
+    // toggles the LED right away and shortens the period: 250, 125, 63 ms
+    assert_eq!(&toggles[3..6], &[1000, 1120, 1240]);
+
+    let last_period = toggles[toggles.len() - 1] - toggles[toggles.len() - 2];
+    assert_eq!(last_period, 63);
+}
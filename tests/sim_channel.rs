@@ -0,0 +1,414 @@
+//! Host simulation of the channel's receive races
+//!
+//! A queued item is received on the first poll, a receiver polled from a new
+//! task is woken there, and a `receive` dropped by `select_biased!` neither
+//! loses an item nor keeps the next one from being received right away. The
+//! `delay` it races against leaves no deadline behind when it loses.
+//! Once the last sender is dropped or one closes the channel, the receiver
+//! gets the remaining items and then `Closed`, and waiting senders give up.
+//! More senders than fit in line are woken instead of lost, and a dropped
+//! `send` leaves the line, also with a second `send` waiting in the same task.
+//! With `MpmcChannel`, every waiting receiver is woken for its own item, also
+//! with several receives waiting in one task, and a pool of workers takes jobs
+//! in turns.
+//!
+//! cargo test --test sim_channel --features std --target x86_64-unknown-linux-gnu
+
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::Wake,
+};
+
+use futures::{select_biased, FutureExt};
+
+use zero_to_async::{
+    channel::{Channel, Closed, SendError, TrySendError},
+    executor,
+    mpmc::{MpmcChannel, Receiver},
+    sim,
+    ticker::{self, TickDuration, Ticker},
+};
+
+mod common;
+use common::at_millis;
+
+// Waker that counts how often it was woken
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl CountingWaker {
+    fn wakes(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// An item that is already there is returned without waiting
+#[test]
+fn first_poll() {
+    let channel: Channel<u32, 2> = Channel::new();
+    let mut receiver = channel.get_receiver();
+    channel.try_send(1).unwrap();
+
+    let mut cx = Context::from_waker(Waker::noop());
+    let received = pin!(receiver.receive()).poll(&mut cx);
+    println!("First poll with an item queued: {:?}", received);
+    assert_eq!(received, Poll::Ready(Ok(1)));
+}
+
+/// Polling from a new task moves the wake up there
+#[test]
+fn waker_refresh() {
+    let channel: Channel<u32, 2> = Channel::new();
+    let mut receiver = channel.get_receiver();
+    let old = Arc::new(CountingWaker::default());
+    let new = Arc::new(CountingWaker::default());
+
+    let mut receive = pin!(receiver.receive());
+    assert!(receive.as_mut().poll(&mut Context::from_waker(&old.clone().into())).is_pending());
+    assert!(receive.as_mut().poll(&mut Context::from_waker(&new.clone().into())).is_pending());
+    channel.try_send(1).unwrap();
+
+    println!("Wakes after moving the receiver: old {}, new {}", old.wakes(), new.wakes());
+    assert_eq!((old.wakes(), new.wakes()), (0, 1));
+}
+
+/// A receive dropped while waiting leaves nothing behind
+#[test]
+fn dropped_receive() {
+    let channel: Channel<u32, 2> = Channel::new();
+    let mut receiver = channel.get_receiver();
+    let mut cx = Context::from_waker(Waker::noop());
+
+    assert!(pin!(receiver.receive()).poll(&mut cx).is_pending());
+    channel.try_send(1).unwrap();
+
+    let received = pin!(receiver.receive()).poll(&mut cx);
+    println!("First poll after a dropped receive: {:?}", received);
+    assert_eq!(received, Poll::Ready(Ok(1)));
+}
+
+/// Receiving with a timeout gets every item when it is sent
+#[test]
+fn select_timeout() {
+    let _sim = common::start();
+
+    let channel: Channel<u32, 2> = Channel::new();
+    let sender = channel.get_sender();
+    let mut receiver = channel.get_receiver();
+    let received = RefCell::new(Vec::new());
+    let timeouts = RefCell::new(0);
+
+    let receiver_task = pin!(async {
+        while received.borrow().len() < 3 {
+            select_biased! {
+                item = receiver.receive().fuse() => {
+                    received.borrow_mut().push((Ticker::now().ticks(), item.unwrap()));
+                }
+                _ = ticker::delay(TickDuration::millis(10)).fuse() => {
+                    *timeouts.borrow_mut() += 1;
+                }
+            }
+        }
+    });
+    let sender_task = pin!(async {
+        for (item, millis) in [(1, 15), (2, 20), (3, 5)] {
+            ticker::delay(TickDuration::millis(millis)).await;
+            sender.send(item).await.unwrap();
+        }
+    });
+    sim::run_until(at_millis(100), &mut [receiver_task, sender_task]);
+
+    let expected: Vec<_> = [(15, 1), (35, 2), (40, 3)]
+        .into_iter()
+        .map(|(millis, item)| (at_millis(millis).ticks(), item))
+        .collect();
+    println!("Received {:?} after {} timeouts", received.borrow(), timeouts.borrow());
+    assert_eq!(*received.borrow(), expected);
+}
+
+/// Racing `receive` against `delay` many more times than there are deadline
+/// slots leaves no deadlines behind, whichever side wins
+#[test]
+fn select_timeout_no_leak() {
+    const ROUNDS: u32 = 40;
+    let _sim = common::start();
+
+    let channel: Channel<u32, 2> = Channel::new();
+    let sender = channel.get_sender();
+    let mut receiver = channel.get_receiver();
+    let received = RefCell::new(Vec::new());
+    let timeouts = Cell::new(0);
+    let most_waiting = Cell::new(0);
+
+    let receiver_task = pin!(async {
+        while received.borrow().len() < ROUNDS as usize {
+            select_biased! {
+                item = receiver.receive().fuse() => received.borrow_mut().push(item.unwrap()),
+                _ = ticker::delay(TickDuration::millis(5)).fuse() => timeouts.set(timeouts.get() + 1),
+            }
+            // The losing side is dropped, only the sender's delay can still wait
+            let waiting = critical_section::with(Ticker::waiting_timers);
+            most_waiting.set(most_waiting.get().max(waiting));
+        }
+    });
+    let sender_task = pin!(async {
+        // Items come before and after the receiver's timeout in turns
+        for item in 0..ROUNDS {
+            ticker::delay(TickDuration::millis(if item % 2 == 0 { 3 } else { 7 })).await;
+            sender.send(item).await.unwrap();
+        }
+    });
+    sim::run_until(at_millis(1000), &mut [receiver_task, sender_task]);
+
+    println!("Received {} items after {} timeouts", received.borrow().len(), timeouts.get());
+    assert_eq!(*received.borrow(), (0..ROUNDS).collect::<Vec<_>>());
+    assert_eq!(timeouts.get(), ROUNDS / 2);
+    assert!(most_waiting.get() <= 1, "{} timers left waiting", most_waiting.get());
+    assert_eq!(critical_section::with(Ticker::waiting_timers), 0);
+}
+
+/// The receiver gets every item sent before the last sender was dropped, then `Closed`
+#[test]
+fn last_sender_dropped() {
+    let _sim = common::start();
+
+    let channel: Channel<u32, 4> = Channel::new();
+    let mut receiver = channel.get_receiver();
+    let first = channel.get_sender();
+    let second = first.clone();
+    let received = RefCell::new(Vec::new());
+    let closed_at = Cell::new(None);
+
+    let receiver_task = pin!(async {
+        while let Ok(item) = receiver.receive().await {
+            received.borrow_mut().push(item);
+        }
+        closed_at.set(Some(Ticker::now().ticks()));
+    });
+    let first_task = pin!(async move {
+        first.send(1).await.unwrap();
+        ticker::delay(TickDuration::millis(5)).await;
+    });
+    let second_task = pin!(async move {
+        ticker::delay(TickDuration::millis(10)).await;
+        second.send(2).await.unwrap();
+        second.send(3).await.unwrap();
+    });
+    sim::run_until(at_millis(100), &mut [receiver_task, first_task, second_task]);
+
+    println!("Received {:?}, closed at {:?}", received.borrow(), closed_at.get());
+    assert_eq!(*received.borrow(), [1, 2, 3]);
+    assert_eq!(closed_at.get(), Some(at_millis(10).ticks()));
+}
+
+/// Closing hands the item back to a sender waiting for space
+#[test]
+fn close_wakes_senders() {
+    let _sim = common::start();
+
+    let channel: Channel<u32, 1> = Channel::new();
+    let mut receiver = channel.get_receiver();
+    let waiting = channel.get_sender();
+    let closing = channel.get_sender();
+    let send_result = Cell::new(None);
+    let received = RefCell::new(Vec::new());
+
+    let waiting_task = pin!(async {
+        waiting.send(1).await.unwrap();
+        // Full, waits until the channel is closed
+        let result = waiting.send(2).await;
+        send_result.set(Some((Ticker::now().ticks(), result)));
+    });
+    let closing_task = pin!(async {
+        ticker::delay(TickDuration::millis(5)).await;
+        closing.close();
+        assert_eq!(closing.try_send(3), Err(TrySendError::Closed(3)));
+    });
+    let receiver_task = pin!(async {
+        ticker::delay(TickDuration::millis(10)).await;
+        for _ in 0..2 {
+            let result = receiver.receive().await;
+            received.borrow_mut().push(result);
+        }
+    });
+    sim::run_until(at_millis(100), &mut [waiting_task, closing_task, receiver_task]);
+
+    println!("Waiting sender got {:?}, receiver got {:?}", send_result.get(), received.borrow());
+    assert_eq!(send_result.get(), Some((at_millis(5).ticks(), Err(SendError(2)))));
+    assert_eq!(*received.borrow(), [Ok(1), Err(Closed)]);
+}
+
+/// Senders that do not fit in line check again, a dropped send is not woken
+#[test]
+fn many_waiting_senders() {
+    let channel: Channel<u32, 1> = Channel::new();
+    let sender = channel.get_sender();
+    let mut receiver = channel.get_receiver();
+    sender.try_send(0).unwrap();
+
+    let wakers: Vec<_> = (0..10).map(|_| Arc::new(CountingWaker::default())).collect();
+    let mut sends: Vec<_> = (0..10).map(|item| Box::pin(sender.send(item))).collect();
+    for (send, waker) in sends.iter_mut().zip(&wakers) {
+        assert!(send.as_mut().poll(&mut Context::from_waker(&waker.clone().into())).is_pending());
+    }
+    // The line holds 8, so the 9th wakes the first 8 to check again
+    sends.pop();
+
+    let received = pin!(receiver.receive()).poll(&mut Context::from_waker(Waker::noop()));
+    assert_eq!(received, Poll::Ready(Ok(0)));
+
+    let wakes: Vec<_> = wakers.iter().map(|waker| waker.wakes()).collect();
+    println!("Wakes of 10 waiting senders, the last one dropped: {:?}", wakes);
+    assert_eq!(wakes, [1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+}
+
+/// Two sends waiting in one task each have their own place, dropping one keeps the other in line
+#[test]
+fn sends_in_one_task() {
+    let channel: Channel<u32, 1> = Channel::new();
+    let sender = channel.get_sender();
+    let mut receiver = channel.get_receiver();
+    sender.try_send(0).unwrap();
+
+    // Both sends run in the same task, so they have the same waker
+    let task = Arc::new(CountingWaker::default());
+    let waker: Waker = task.clone().into();
+    let mut first = Box::pin(sender.send(1));
+    let mut second = Box::pin(sender.send(2));
+    assert!(first.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    assert!(second.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    drop(first);
+
+    let received = pin!(receiver.receive()).poll(&mut Context::from_waker(Waker::noop()));
+    assert_eq!(received, Poll::Ready(Ok(0)));
+
+    // The second send is woken and gets the space
+    println!("Wakes of the task after the first send was dropped: {}", task.wakes());
+    assert_eq!(task.wakes(), 1);
+    assert_eq!(second.as_mut().poll(&mut Context::from_waker(&waker)), Poll::Ready(Ok(())));
+}
+
+/// A second waiting receiver does not take the wake up away from the first
+#[test]
+fn receivers_all_woken() {
+    let _sim = common::start();
+
+    static CHANNEL: MpmcChannel<u32, 2> = MpmcChannel::new();
+    let sender = CHANNEL.get_sender();
+    let first = CHANNEL.get_receiver();
+    let second = first.clone();
+    let received = RefCell::new(Vec::new());
+
+    let receiver = |name: char, receiver: Receiver<'static, u32, 2>| {
+        let received = &received;
+        async move {
+            let item = receiver.receive().await.unwrap();
+            received.borrow_mut().push((name, Ticker::now().ticks(), item));
+        }
+    };
+    let first_task = pin!(receiver('a', first));
+    let second_task = pin!(receiver('b', second));
+    let sender_task = pin!(async {
+        ticker::delay(TickDuration::millis(5)).await;
+        sender.try_send(1).unwrap();
+        sender.try_send(2).unwrap();
+    });
+    sim::run_until(at_millis(100), &mut [first_task, second_task, sender_task]);
+
+    let at = at_millis(5).ticks();
+    println!("Receivers got {:?}", received.borrow());
+    assert_eq!(*received.borrow(), [('a', at, 1), ('b', at, 2)]);
+}
+
+/// Two receives waiting in one task each have their own place, neither a
+/// dropped one nor one done ahead of its turn takes a wake up from another task
+#[test]
+fn receives_in_one_task() {
+    let channel: MpmcChannel<u32, 2> = MpmcChannel::new();
+    let sender = channel.get_sender();
+    let receiver = channel.get_receiver();
+
+    let task = Arc::new(CountingWaker::default());
+    let other_task = Arc::new(CountingWaker::default());
+    let (waker, other_waker): (Waker, Waker) = (task.clone().into(), other_task.clone().into());
+    let mut first = Box::pin(receiver.receive());
+    let mut second = Box::pin(receiver.receive());
+    let mut third = Box::pin(receiver.receive());
+    assert!(first.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    assert!(second.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    assert!(third.as_mut().poll(&mut Context::from_waker(&other_waker)).is_pending());
+
+    // Woken for the first receive, but the second one takes the item
+    sender.try_send(1).unwrap();
+    assert_eq!(second.as_mut().poll(&mut Context::from_waker(&waker)), Poll::Ready(Ok(1)));
+    drop(first);
+
+    // The next item is the turn of the receive in the other task
+    sender.try_send(2).unwrap();
+    println!("Wakes of the task: {}, of the other task: {}", task.wakes(), other_task.wakes());
+    assert_eq!((task.wakes(), other_task.wakes()), (1, 1));
+    assert_eq!(third.as_mut().poll(&mut Context::from_waker(&other_waker)), Poll::Ready(Ok(2)));
+
+    // Dropping one of two waiting receives leaves the other in line
+    let mut first = Box::pin(receiver.receive());
+    let mut second = Box::pin(receiver.receive());
+    assert!(first.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    assert!(second.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    drop(first);
+    sender.try_send(3).unwrap();
+    assert_eq!(task.wakes(), 2);
+    assert_eq!(second.as_mut().poll(&mut Context::from_waker(&waker)), Poll::Ready(Ok(3)));
+}
+
+/// Workers waiting on one queue take the jobs in turns, also more than the
+/// 4 tasks `run_until` takes, so some are spawned
+#[test]
+fn worker_pool() {
+    static CHANNEL: MpmcChannel<u32, 2> = MpmcChannel::new();
+    static DONE: Mutex<Vec<(u32, char)>> = Mutex::new(Vec::new());
+
+    async fn worker(name: char, receiver: Receiver<'static, u32, 2>) {
+        while let Ok(job) = receiver.receive().await {
+            ticker::delay(TickDuration::millis(10)).await;
+            DONE.lock().unwrap().push((job, name));
+        }
+    }
+
+    let _sim = common::start();
+
+    let sender = CHANNEL.get_sender();
+    let a = pin!(worker('a', CHANNEL.get_receiver()));
+    let b = pin!(worker('b', CHANNEL.get_receiver()));
+    let c = pin!(worker('c', CHANNEL.get_receiver()));
+    for name in ['d', 'e'] {
+        executor::spawner().spawn(worker(name, CHANNEL.get_receiver())).unwrap();
+    }
+    let producer = pin!(async move {
+        for job in 0..10 {
+            sender.send(job).await.unwrap();
+        }
+    });
+    sim::run_until(at_millis(100), &mut [a, b, c, producer]);
+
+    let done = DONE.lock().unwrap();
+    let workers: String = done.iter().map(|(_, name)| name).collect();
+    println!("Jobs done by {}", workers);
+    // Spawned tasks are polled first, so they line up first
+    assert_eq!(workers, "deabcdeabc");
+    assert!(done.iter().map(|(job, _)| *job).eq(0..10));
+}
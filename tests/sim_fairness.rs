@@ -0,0 +1,80 @@
+//! Host simulation of tasks sharing the CPU
+//!
+//! Three busy tasks that call `yield_now` take turns in a fixed order. A task
+//! waiting on an input level that never changes would spin forever, its
+//! poll budget makes it give the other task a turn after every 3 waits.
+//!
+//! cargo test --test sim_fairness --features std --target x86_64-unknown-linux-gnu
+
+use core::{cell::{Cell, RefCell}, pin::pin};
+use embedded_hal::digital::PinState;
+
+use zero_to_async::{
+    button_interrupt::InputChannel,
+    executor::{self, yield_now},
+    sim::{self, SimInput},
+};
+
+mod common;
+use common::at_millis;
+
+const INPUT_EXTI_LINE: usize = 13;
+
+/// Busy tasks that yield run in turns
+#[test]
+fn round_robin() {
+    let _sim = common::start();
+
+    let order = RefCell::new(Vec::new());
+    let busy = |name: char| {
+        let order = &order;
+        async move {
+            for _ in 0..4 {
+                order.borrow_mut().push(name);
+                yield_now().await;
+            }
+        }
+    };
+
+    let a = pin!(busy('a'));
+    let b = pin!(busy('b'));
+    let c = pin!(busy('c'));
+    sim::run_until(at_millis(10), &mut [a, b, c]);
+
+    let order: String = order.borrow().iter().collect();
+    println!("Yielding tasks ran as {}", order);
+    assert_eq!(order, "abcabcabcabc");
+}
+
+/// A task whose futures are always ready still lets the others run
+#[test]
+fn poll_budget() {
+    let _sim = common::start();
+
+    let order = RefCell::new(Vec::new());
+    let done = Cell::new(false);
+
+    // The input stays high, so waiting for high is always ready
+    let mut input = InputChannel::new_sim(SimInput::new(INPUT_EXTI_LINE));
+    let spinner = pin!(async {
+        executor::set_poll_budget(Some(3)).await;
+
+        while !done.get() {
+            input.wait_for(PinState::High).await;
+            order.borrow_mut().push('s');
+        }
+    });
+    let worker = pin!(async {
+        for _ in 0..3 {
+            order.borrow_mut().push('w');
+            yield_now().await;
+        }
+        done.set(true);
+    });
+    sim::run_until(at_millis(10), &mut [spinner, worker]);
+
+    let order: String = order.borrow().iter().collect();
+    println!("Spinner and worker ran as {}", order);
+    // 3 waits per poll, the last one finishes the wait it ran out of budget in
+    assert_eq!(order, "sssw sssw sssw sss s".replace(' ', ""));
+}
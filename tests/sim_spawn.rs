@@ -0,0 +1,52 @@
+//! Host simulation of spawned tasks and their JoinHandles
+//!
+//! A JoinHandle returns its task's output, and one left over from before a
+//! reset panics instead of reading the output of a task spawned since.
+//!
+//! cargo test --test sim_spawn --features std --target x86_64-unknown-linux-gnu
+
+use core::{cell::Cell, pin::pin};
+
+use zero_to_async::{
+    executor,
+    sim,
+    ticker::{self, TickDuration},
+};
+
+mod common;
+use common::at_millis;
+
+/// Awaiting a JoinHandle gives the output of the spawned task
+#[test]
+fn join_output() {
+    let _sim = common::start();
+
+    let output = Cell::new(None);
+    let handle = executor::spawner().spawn(async { [1u64, 2, 3] }).unwrap();
+    let task = pin!(async {
+        output.set(Some(handle.await));
+    });
+    sim::run_until(at_millis(10), &mut [task]);
+
+    assert_eq!(output.get(), Some([1, 2, 3]));
+}
+
+/// A JoinHandle kept across a reset does not read the task now in its slot
+#[test]
+#[should_panic(expected = "JoinHandle of a task dropped by Executor::reset")]
+fn join_after_reset() {
+    let _sim = common::start();
+
+    let handle = executor::spawner().spawn(async { [1u64, 2, 3] }).unwrap();
+    sim::init();
+
+    // Takes over the slot of the dropped task
+    executor::spawner().spawn(async {
+        ticker::delay(TickDuration::millis(1)).await;
+        7u8
+    }).unwrap();
+    let task = pin!(async {
+        handle.await;
+    });
+    sim::run_until(at_millis(10), &mut [task]);
+}
@@ -0,0 +1,173 @@
+//! Wraparound of the TIM2 driver's 64-bit time
+//!
+//! A simulated 32-bit counter with an overflow flag and an update interrupt
+//! runs the same arithmetic as the driver. Reads with a pending overflow give
+//! the right period whether the counter was read before or after the wrap,
+//! the overflow count wraps at `u32::MAX` without panicking, and an alarm in
+//! the next cycle is only armed once the update interrupt has run. An alarm
+//! the counter passes while its compare value is written is handled right away.
+//!
+//! cargo test --test tim2_counter --features std --target x86_64-unknown-linux-gnu
+
+use std::cell::RefCell;
+
+use zero_to_async::time_driver::counter::{arm_alarm, compare_for, extend_counter, Compare, COUNTER_MAX};
+
+const CYCLE: u64 = 1 << 32;
+
+// TIM2 as the driver sees it: counter, UIF and the software overflow count
+struct SimCounter {
+    counter: u32,
+    overflow_pending: bool,
+    periods: u32,
+    alarm: u64,
+    compare: Option<u32>,
+    write_ticks: u32, // Counted while the compare value is written
+}
+
+impl SimCounter {
+    fn new(periods: u32, counter: u32) -> Self {
+        Self { counter, overflow_pending: false, periods, alarm: u64::MAX, compare: None, write_ticks: 0 }
+    }
+
+    fn now(&self) -> u64 {
+        extend_counter(self.periods, self.counter, self.overflow_pending)
+    }
+
+    /// Count `ticks`, setting UIF when the counter wraps
+    fn advance(&mut self, ticks: u32) {
+        let (counter, wrapped) = self.counter.overflowing_add(ticks);
+        self.counter = counter;
+        self.overflow_pending |= wrapped;
+    }
+
+    /// Returns false if the alarm already passed, like the driver's `set_alarm`
+    fn set_alarm(&mut self, alarm: u64) -> bool {
+        self.alarm = alarm;
+        self.arm_compare()
+    }
+
+    fn arm_compare(&mut self) -> bool {
+        let alarm = self.alarm;
+        let timer = RefCell::new(self);
+        let decision = arm_alarm(
+            alarm,
+            || {
+                let timer = timer.borrow();
+                (timer.periods, timer.counter, timer.overflow_pending)
+            },
+            |compare| {
+                let mut timer = timer.borrow_mut();
+                timer.compare = Some(compare);
+                let ticks = timer.write_ticks;
+                timer.advance(ticks);
+            },
+        );
+
+        let timer = timer.into_inner();
+        if !matches!(decision, Compare::Arm(_)) {
+            timer.compare = None;
+        }
+        decision != Compare::Passed
+    }
+
+    /// TIM2 update interrupt: count the overflow and arm the alarm if it is now due this cycle
+    fn update_interrupt(&mut self) -> bool {
+        self.overflow_pending = false;
+        self.periods = self.periods.wrapping_add(1);
+        self.arm_compare()
+    }
+}
+
+/// A low counter with the overflow pending has wrapped already, the period is one ahead
+#[test]
+fn overflow_pending_low_counter() {
+    let mut timer = SimCounter::new(7, COUNTER_MAX - 2);
+    timer.advance(5);
+
+    assert!(timer.overflow_pending);
+    assert_eq!(timer.counter, 2);
+    assert_eq!(timer.now(), 8 * CYCLE + 2);
+
+    // The update interrupt gives the same time
+    timer.update_interrupt();
+    assert_eq!(timer.now(), 8 * CYCLE + 2);
+}
+
+/// A high counter with the overflow pending was read before the wrap, the period is unchanged
+#[test]
+fn overflow_pending_high_counter() {
+    // The counter read just before the wrap, UIF read just after it
+    assert_eq!(extend_counter(7, COUNTER_MAX, true), 7 * CYCLE + COUNTER_MAX as u64);
+    assert_eq!(extend_counter(7, COUNTER_MAX / 2, true), 7 * CYCLE + (COUNTER_MAX / 2) as u64);
+
+    // Without an overflow pending the reading is taken as is
+    assert_eq!(extend_counter(7, 2, false), 7 * CYCLE + 2);
+}
+
+/// The overflow count wraps like the hardware counter instead of overflowing
+#[test]
+fn periods_at_max() {
+    assert_eq!(extend_counter(u32::MAX, COUNTER_MAX, false), u64::MAX);
+    assert_eq!(extend_counter(u32::MAX, COUNTER_MAX, true), u64::MAX);
+
+    let mut timer = SimCounter::new(u32::MAX, COUNTER_MAX);
+    timer.advance(3);
+    assert_eq!(timer.now(), 2);
+
+    timer.update_interrupt();
+    assert_eq!(timer.periods, 0);
+    assert_eq!(timer.now(), 2);
+}
+
+/// An alarm in the next cycle waits for the update interrupt to be armed
+#[test]
+fn next_cycle_alarm_armed_on_update() {
+    let mut timer = SimCounter::new(3, COUNTER_MAX - 100);
+    let alarm = 4 * CYCLE + 50;
+
+    // In the previous cycle the compare value would match 50 ticks into this cycle
+    assert!(timer.set_alarm(alarm));
+    assert_eq!(timer.compare, None);
+
+    // Wrapped, but the update interrupt has not run yet
+    timer.advance(120);
+    assert_eq!(timer.now(), 4 * CYCLE + 19);
+    assert_eq!(timer.compare, None);
+
+    assert!(timer.update_interrupt());
+    assert_eq!(timer.compare, Some(50));
+
+    // Passed by the time the update interrupt runs, so it is handled right away
+    let mut timer = SimCounter::new(3, COUNTER_MAX - 100);
+    assert!(timer.set_alarm(alarm));
+    timer.advance(200);
+    assert!(!timer.update_interrupt());
+    assert_eq!(timer.compare, None);
+}
+
+/// An alarm the counter passes while its compare value is written would only
+/// match a cycle later, so it is handled right away
+#[test]
+fn alarm_passed_while_arming() {
+    let mut timer = SimCounter::new(3, 1000);
+    timer.write_ticks = 10;
+
+    assert!(timer.set_alarm(3 * CYCLE + 1020));
+    assert_eq!(timer.compare, Some(1020));
+
+    assert!(!timer.set_alarm(3 * CYCLE + 1015));
+    assert_eq!(timer.compare, None);
+}
+
+/// Alarms are armed, handled or deferred by the cycle they fall in
+#[test]
+fn compare_decision() {
+    let now = 5 * CYCLE + 1000;
+
+    assert_eq!(compare_for(now - 1, now), Compare::Passed);
+    assert_eq!(compare_for(now, now), Compare::Passed);
+    assert_eq!(compare_for(now + 1, now), Compare::Arm(1001));
+    assert_eq!(compare_for(6 * CYCLE - 1, now), Compare::Arm(COUNTER_MAX));
+    assert_eq!(compare_for(6 * CYCLE, now), Compare::Later);
+}